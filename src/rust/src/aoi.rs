@@ -0,0 +1,517 @@
+use extendr_api::prelude::*;
+
+use crate::errors::AoiError;
+use crate::{StationMetadataSet, EARTH_RADIUS};
+
+// AREA OF INTEREST ------------------------------------------------------------
+// stations are kept if their longitude and latitude fall inside a polygon or
+// multipolygon, or within `buffer` km of its boundary, so the station list is
+// trimmed before any R objects are built. the area comes from R as WKB (what
+// `sf::st_as_binary()` gives), as WKT, or as the coordinate matrices of an
+// sfg, always in WGS 84 longitude and latitude.
+//
+// containment is an even-odd ray cast over every ring of a polygon, so holes
+// are excluded however their rings are wound, and a point on any edge counts
+// as inside, as it does for `sf::st_intersects()`. edges are straight lines in
+// longitude and latitude, and distances to them are measured on a plane
+// tangent to the earth at the station, which is plenty close at buffer
+// distances of a few hundred km.
+
+type Ring = Vec<(f64, f64)>;
+
+// km per degree of latitude
+const KM_PER_DEGREE: f64 = EARTH_RADIUS * std::f64::consts::PI / 180.0;
+
+// an exterior ring followed by any holes
+#[derive(Debug, Clone)]
+struct Polygon {
+    rings: Vec<Ring>,
+    // xmin, ymin, xmax, ymax
+    bbox: [f64; 4],
+}
+
+impl Polygon {
+    fn new(rings: Vec<Ring>) -> Self {
+        let mut bbox = [
+            f64::INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NEG_INFINITY,
+        ];
+
+        for &(x, y) in rings.iter().flatten() {
+            bbox = [
+                bbox[0].min(x),
+                bbox[1].min(y),
+                bbox[2].max(x),
+                bbox[3].max(y),
+            ];
+        }
+
+        Polygon { rings, bbox }
+    }
+
+    fn contains(&self, x: f64, y: f64) -> bool {
+        let [xmin, ymin, xmax, ymax] = self.bbox;
+
+        if x < xmin || x > xmax || y < ymin || y > ymax {
+            return false;
+        }
+
+        let mut inside = false;
+
+        for ring in self.rings.iter() {
+            for (a, b) in edges(ring) {
+                if is_on_segment((x, y), a, b) {
+                    return true;
+                }
+
+                if (a.1 > y) != (b.1 > y) && x < a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+                    inside = !inside;
+                }
+            }
+        }
+
+        inside
+    }
+
+    // whether any edge comes within `km` of the point
+    fn is_within(&self, x: f64, y: f64, km: f64) -> bool {
+        // a rough check against the bbox first, widened by the buffer in
+        // degrees at this latitude
+        let dy = km / KM_PER_DEGREE;
+        let dx = dy / y.to_radians().cos().max(1e-6);
+        let [xmin, ymin, xmax, ymax] = self.bbox;
+
+        if x < xmin - dx || x > xmax + dx || y < ymin - dy || y > ymax + dy {
+            return false;
+        }
+
+        let project = |p: &(f64, f64)| {
+            let mut lon = p.0 - x;
+
+            if lon > 180.0 {
+                lon -= 360.0;
+            } else if lon < -180.0 {
+                lon += 360.0;
+            }
+
+            (
+                lon * y.to_radians().cos() * KM_PER_DEGREE,
+                (p.1 - y) * KM_PER_DEGREE,
+            )
+        };
+
+        self.rings
+            .iter()
+            .any(|ring| edges(ring).any(|(a, b)| distance_to_segment(project(a), project(b)) <= km))
+    }
+}
+
+// each edge of a ring, closing it if the last point isn't the first
+fn edges(ring: &Ring) -> impl Iterator<Item = (&(f64, f64), &(f64, f64))> {
+    let n = ring.len();
+
+    (0..n).map(move |i| (&ring[i], &ring[(i + 1) % n]))
+}
+
+// whether p lies on the segment a-b: in line with it, and inside its bbox
+fn is_on_segment(p: (f64, f64), a: &(f64, f64), b: &(f64, f64)) -> bool {
+    let cross = (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+
+    cross == 0.0
+        && p.0 >= a.0.min(b.0)
+        && p.0 <= a.0.max(b.0)
+        && p.1 >= a.1.min(b.1)
+        && p.1 <= a.1.max(b.1)
+}
+
+// distance from the origin to the segment a-b
+fn distance_to_segment(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+
+    let t = if length == 0.0 {
+        0.0
+    } else {
+        (-(a.0 * dx + a.1 * dy) / length).clamp(0.0, 1.0)
+    };
+
+    (a.0 + t * dx).hypot(a.1 + t * dy)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Aoi {
+    polygons: Vec<Polygon>,
+    // km
+    buffer: f64,
+}
+
+impl Aoi {
+    // from R: NULL for no area of interest, a raw vector of WKB, a WKT string,
+    // or the list of coordinate matrices in a POLYGON or MULTIPOLYGON sfg
+    pub(crate) fn from_robj(x: &Robj, buffer: f64) -> std::result::Result<Option<Self>, AoiError> {
+        if x.is_null() {
+            return Ok(None);
+        }
+
+        let polygons = if let Some(bytes) = x.as_raw_slice() {
+            Wkb::new(bytes).read()?
+        } else if let Some(text) = x.as_str() {
+            Wkt::new(text).read()?
+        } else if let Ok(lst) = List::try_from(x) {
+            from_list(&lst)?
+        } else {
+            return Err(AoiError::new("expected WKB, WKT, or polygon coordinates"));
+        };
+
+        if !buffer.is_finite() || buffer < 0.0 {
+            return Err(AoiError::new(
+                "buffer must be a distance of zero km or more",
+            ));
+        }
+
+        Ok(Some(Aoi {
+            polygons: polygons.into_iter().map(Polygon::new).collect(),
+            buffer,
+        }))
+    }
+
+    pub(crate) fn contains(&self, longitude: f64, latitude: f64) -> bool {
+        self.polygons
+            .iter()
+            .any(|p| p.contains(longitude, latitude))
+            || (self.buffer > 0.0
+                && self
+                    .polygons
+                    .iter()
+                    .any(|p| p.is_within(longitude, latitude, self.buffer)))
+    }
+}
+
+impl StationMetadataSet {
+    // drop the stations outside the area of interest
+    pub(crate) fn retain_in(&mut self, aoi: &Aoi) {
+        self.0.retain(|s| aoi.contains(s.longitude, s.latitude));
+    }
+}
+
+// WKB -------------------------------------------------------------------------
+// ISO and extended (PostGIS) well-known binary, in either byte order. only
+// the x and y of each point are kept.
+struct Wkb<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+const WKB_POLYGON: u32 = 3;
+const WKB_MULTIPOLYGON: u32 = 6;
+
+impl<'a> Wkb<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Wkb { bytes, at: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> std::result::Result<&'a [u8], AoiError> {
+        let end = self.at + n;
+        let out = self
+            .bytes
+            .get(self.at..end)
+            .ok_or_else(|| AoiError::new("WKB ends early"))?;
+
+        self.at = end;
+
+        Ok(out)
+    }
+
+    fn u32(&mut self, little: bool) -> std::result::Result<u32, AoiError> {
+        let b: [u8; 4] = self.take(4)?.try_into().unwrap();
+
+        Ok(if little {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn f64(&mut self, little: bool) -> std::result::Result<f64, AoiError> {
+        let b: [u8; 8] = self.take(8)?.try_into().unwrap();
+
+        Ok(if little {
+            f64::from_le_bytes(b)
+        } else {
+            f64::from_be_bytes(b)
+        })
+    }
+
+    // byte order, geometry type, and number of coordinates per point
+    fn header(&mut self) -> std::result::Result<(bool, u32, usize), AoiError> {
+        let little = match self.take(1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(AoiError::new("invalid WKB byte order")),
+        };
+
+        let code = self.u32(little)?;
+
+        // extended WKB flags dimensions in the high bits, and may carry an srid
+        let mut dims = 2;
+
+        if code & 0x8000_0000 != 0 {
+            dims += 1;
+        }
+
+        if code & 0x4000_0000 != 0 {
+            dims += 1;
+        }
+
+        if code & 0x2000_0000 != 0 {
+            self.u32(little)?;
+        }
+
+        // ISO WKB adds 1000 for Z, 2000 for M, and 3000 for ZM
+        let code = code & 0x0fff_ffff;
+
+        dims += match code / 1000 {
+            1 | 2 => 1,
+            3 => 2,
+            _ => 0,
+        };
+
+        Ok((little, code % 1000, dims))
+    }
+
+    fn polygon(&mut self, little: bool, dims: usize) -> std::result::Result<Vec<Ring>, AoiError> {
+        let n_rings = self.u32(little)?;
+        let mut rings = Vec::new();
+
+        for _ in 0..n_rings {
+            let n_points = self.u32(little)?;
+            let mut ring = Vec::new();
+
+            for _ in 0..n_points {
+                let x = self.f64(little)?;
+                let y = self.f64(little)?;
+
+                for _ in 2..dims {
+                    self.f64(little)?;
+                }
+
+                ring.push((x, y));
+            }
+
+            rings.push(ring);
+        }
+
+        Ok(rings)
+    }
+
+    fn read(mut self) -> std::result::Result<Vec<Vec<Ring>>, AoiError> {
+        let (little, kind, dims) = self.header()?;
+
+        match kind {
+            WKB_POLYGON => Ok(vec![self.polygon(little, dims)?]),
+            WKB_MULTIPOLYGON => {
+                let n = self.u32(little)?;
+                let mut polygons = Vec::new();
+
+                for _ in 0..n {
+                    let (little, kind, dims) = self.header()?;
+
+                    if kind != WKB_POLYGON {
+                        return Err(AoiError::new("multipolygon holds a non-polygon"));
+                    }
+
+                    polygons.push(self.polygon(little, dims)?);
+                }
+
+                Ok(polygons)
+            }
+            _ => Err(AoiError::new("WKB is not a polygon or multipolygon")),
+        }
+    }
+}
+
+// WKT -------------------------------------------------------------------------
+// "POLYGON ((x y, ...), (x y, ...))" or "MULTIPOLYGON (((x y, ...)), ...)",
+// with any Z, M or ZM tag, an EMPTY geometry, or a leading "SRID=n;"
+struct Wkt<'a> {
+    text: &'a str,
+}
+
+impl<'a> Wkt<'a> {
+    fn new(text: &'a str) -> Self {
+        let text = text.trim();
+        let text = match text.split_once(';') {
+            Some((srid, rest)) if srid.trim().to_ascii_uppercase().starts_with("SRID") => rest,
+            _ => text,
+        };
+
+        Wkt { text: text.trim() }
+    }
+
+    fn read(self) -> std::result::Result<Vec<Vec<Ring>>, AoiError> {
+        let split = self
+            .text
+            .find(|c: char| c == '(' || c.eq_ignore_ascii_case(&'E'))
+            .unwrap_or(self.text.len());
+
+        // "POLYGON Z", "MULTIPOLYGONZM" and the like
+        let tag = self.text[..split].to_ascii_uppercase();
+        let tag = tag.trim().trim_end_matches(['Z', 'M']).trim();
+        let body = self.text[split..].trim();
+
+        let is_multi = match tag {
+            "POLYGON" => false,
+            "MULTIPOLYGON" => true,
+            _ => return Err(AoiError::new("WKT is not a polygon or multipolygon")),
+        };
+
+        if body.eq_ignore_ascii_case("EMPTY") {
+            return Ok(Vec::new());
+        }
+
+        let mut chars = body.chars().peekable();
+        let nested = parse_nested(&mut chars)?;
+
+        if chars.any(|c| !c.is_whitespace()) {
+            return Err(AoiError::new("unexpected text after WKT geometry"));
+        }
+
+        let polygon = |n: &Nested| -> std::result::Result<Vec<Ring>, AoiError> {
+            n.children()?.iter().map(|r| r.ring()).collect()
+        };
+
+        if is_multi {
+            nested.children()?.iter().map(polygon).collect()
+        } else {
+            Ok(vec![polygon(&nested)?])
+        }
+    }
+}
+
+// the parenthesized lists of WKT, down to the comma separated points
+enum Nested {
+    List(Vec<Nested>),
+    Points(Ring),
+}
+
+impl Nested {
+    fn children(&self) -> std::result::Result<&[Nested], AoiError> {
+        match self {
+            Nested::List(x) => Ok(x),
+            Nested::Points(_) => Err(AoiError::new("WKT polygon is nested too shallowly")),
+        }
+    }
+
+    fn ring(&self) -> std::result::Result<Ring, AoiError> {
+        match self {
+            Nested::Points(x) => Ok(x.clone()),
+            Nested::List(_) => Err(AoiError::new("WKT polygon is nested too deeply")),
+        }
+    }
+}
+
+fn parse_nested<I>(chars: &mut std::iter::Peekable<I>) -> std::result::Result<Nested, AoiError>
+where
+    I: Iterator<Item = char>,
+{
+    let skip_space = |chars: &mut std::iter::Peekable<I>| {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+    };
+
+    skip_space(chars);
+
+    if chars.next() != Some('(') {
+        return Err(AoiError::new("expected `(` in WKT"));
+    }
+
+    skip_space(chars);
+
+    // a list of lists
+    if chars.peek() == Some(&'(') {
+        let mut children = Vec::new();
+
+        loop {
+            children.push(parse_nested(chars)?);
+            skip_space(chars);
+
+            match chars.next() {
+                Some(',') => continue,
+                Some(')') => return Ok(Nested::List(children)),
+                _ => return Err(AoiError::new("expected `,` or `)` in WKT")),
+            }
+        }
+    }
+
+    // a list of points
+    let mut text = String::new();
+
+    loop {
+        match chars.next() {
+            Some(')') => break,
+            Some(c) => text.push(c),
+            None => return Err(AoiError::new("unclosed `(` in WKT")),
+        }
+    }
+
+    let ring = text
+        .split(',')
+        .map(|point| {
+            let mut xy = point.split_whitespace().map(str::parse::<f64>);
+
+            match (xy.next(), xy.next()) {
+                (Some(Ok(x)), Some(Ok(y))) => Ok((x, y)),
+                _ => Err(AoiError::new(&format!(
+                    "invalid WKT point `{}`",
+                    point.trim()
+                ))),
+            }
+        })
+        .collect::<std::result::Result<Ring, _>>()?;
+
+    Ok(Nested::Points(ring))
+}
+
+// SFG -------------------------------------------------------------------------
+// a POLYGON sfg is a list of two (or more) column matrices, one per ring, and
+// a MULTIPOLYGON is a list of those
+fn from_list(x: &List) -> std::result::Result<Vec<Vec<Ring>>, AoiError> {
+    let is_multi = x.values().next().map_or(false, |v| v.is_list());
+
+    if is_multi {
+        x.values()
+            .map(|p| {
+                List::try_from(&p)
+                    .map_err(|_| AoiError::new("expected a list of polygons"))
+                    .and_then(|p| rings(&p))
+            })
+            .collect()
+    } else {
+        Ok(vec![rings(x)?])
+    }
+}
+
+fn rings(x: &List) -> std::result::Result<Vec<Ring>, AoiError> {
+    x.values()
+        .map(|m| {
+            let values = m
+                .as_real_slice()
+                .ok_or_else(|| AoiError::new("expected numeric coordinate matrices"))?;
+
+            let n = match m.dim() {
+                Some(dim) if dim.len() == 2 && dim[1].inner() >= 2 => dim[0].inner() as usize,
+                _ => {
+                    return Err(AoiError::new(
+                        "expected coordinate matrices with x and y columns",
+                    ))
+                }
+            };
+
+            // column major, so x is the first column and y the second
+            Ok((0..n).map(|i| (values[i], values[n + i])).collect())
+        })
+        .collect()
+}
@@ -0,0 +1,594 @@
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::errors::WriteError;
+use crate::huc::{self, StationHucs};
+use crate::triplet::StationTriplet;
+use crate::{dates, ipc, named_strings, parquet};
+use crate::{
+    parse_forecast_values, ForecastPeriod, ForecastPeriods, ReservoirMetadata, StationDataSet,
+    StationForecastSet, StationMetadata, StationMetadataSet,
+};
+
+// RECORD BATCHES --------------------------------------------------------------
+// parsed station data, forecasts and metadata can be written straight to Arrow
+// IPC or Parquet files without building R objects first. both writers work
+// from the same flat, typed columns, laid out the way Arrow describes them:
+// dates as days, timestamps as seconds since the epoch in UTC, and codes that
+// repeat from row to row dictionary encoded.
+//
+// the writers are kept small on purpose: one record batch (or row group per
+// million rows) and no compression, which every Arrow and Parquet reader
+// understands.
+
+#[derive(Debug, Clone)]
+pub(crate) enum Column {
+    Utf8(Vec<Option<String>>),
+    // utf8 values stored once in a dictionary, and indexed from each row
+    Dictionary(Vec<Option<String>>),
+    Int32(Vec<Option<i32>>),
+    Float64(Vec<Option<f64>>),
+    Boolean(Vec<Option<bool>>),
+    // days since 1970-01-01
+    Date(Vec<Option<i32>>),
+    // seconds since 1970-01-01 UTC
+    Timestamp(Vec<Option<i64>>),
+}
+
+impl Column {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Column::Utf8(x) | Column::Dictionary(x) => x.len(),
+            Column::Int32(x) | Column::Date(x) => x.len(),
+            Column::Float64(x) => x.len(),
+            Column::Boolean(x) => x.len(),
+            Column::Timestamp(x) => x.len(),
+        }
+    }
+
+    pub(crate) fn is_valid(&self, i: usize) -> bool {
+        match self {
+            Column::Utf8(x) | Column::Dictionary(x) => x[i].is_some(),
+            Column::Int32(x) | Column::Date(x) => x[i].is_some(),
+            Column::Float64(x) => x[i].is_some(),
+            Column::Boolean(x) => x[i].is_some(),
+            Column::Timestamp(x) => x[i].is_some(),
+        }
+    }
+
+    pub(crate) fn null_count(&self) -> usize {
+        (0..self.len()).filter(|&i| !self.is_valid(i)).count()
+    }
+
+    // the rows in `range`, for splitting a batch into row groups
+    pub(crate) fn slice(&self, range: std::ops::Range<usize>) -> Column {
+        match self {
+            Column::Utf8(x) => Column::Utf8(x[range].to_vec()),
+            Column::Dictionary(x) => Column::Dictionary(x[range].to_vec()),
+            Column::Int32(x) => Column::Int32(x[range].to_vec()),
+            Column::Float64(x) => Column::Float64(x[range].to_vec()),
+            Column::Boolean(x) => Column::Boolean(x[range].to_vec()),
+            Column::Date(x) => Column::Date(x[range].to_vec()),
+            Column::Timestamp(x) => Column::Timestamp(x[range].to_vec()),
+        }
+    }
+
+    // R's numeric dates, as the dates module produces them
+    pub(crate) fn dates(x: Vec<Option<f64>>) -> Column {
+        Column::Date(x.into_iter().map(|d| d.map(|d| d as i32)).collect())
+    }
+
+    pub(crate) fn timestamps(x: Vec<Option<f64>>) -> Column {
+        Column::Timestamp(x.into_iter().map(|s| s.map(|s| s as i64)).collect())
+    }
+}
+
+pub(crate) struct Field {
+    pub(crate) name: String,
+    pub(crate) nullable: bool,
+    pub(crate) column: Column,
+}
+
+#[derive(Default)]
+pub(crate) struct RecordBatch {
+    pub(crate) fields: Vec<Field>,
+}
+
+impl RecordBatch {
+    // add a column that may have missing values
+    pub(crate) fn push(&mut self, name: &str, column: Column) -> &mut Self {
+        self.fields.push(Field {
+            name: name.to_string(),
+            nullable: true,
+            column,
+        });
+
+        self
+    }
+
+    // add a column that never has missing values, which the schema records.
+    // a column that turns out to have some anyway is marked nullable, so the
+    // file is never wrong about it.
+    pub(crate) fn push_required(&mut self, name: &str, column: Column) -> &mut Self {
+        let nullable = column.null_count() > 0;
+
+        self.fields.push(Field {
+            name: name.to_string(),
+            nullable,
+            column,
+        });
+
+        self
+    }
+
+    pub(crate) fn num_rows(&self) -> usize {
+        self.fields.first().map_or(0, |f| f.column.len())
+    }
+}
+
+// dictionary encode strings, with the dictionary in order of first appearance
+pub(crate) fn dictionary(x: &[Option<String>]) -> (Vec<Option<i32>>, Vec<&str>) {
+    let mut index: HashMap<&str, i32> = HashMap::new();
+    let mut values: Vec<&str> = Vec::new();
+
+    let indices = x
+        .iter()
+        .map(|v| {
+            v.as_deref().map(|v| {
+                *index.entry(v).or_insert_with(|| {
+                    values.push(v);
+                    values.len() as i32 - 1
+                })
+            })
+        })
+        .collect();
+
+    (indices, values)
+}
+
+// WRITING ---------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Arrow,
+    Parquet,
+}
+
+impl Format {
+    pub(crate) fn parse(x: &str) -> Option<Format> {
+        match x.to_ascii_lowercase().as_str() {
+            "arrow" | "ipc" | "feather" => Some(Format::Arrow),
+            "parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+}
+
+// write to a temporary file next to `path` first and move it into place, so
+// readers never see half a file
+pub(crate) fn write_file(
+    batch: &RecordBatch,
+    path: &str,
+    format: Format,
+    row_group_size: usize,
+) -> std::result::Result<(), WriteError> {
+    let bytes = match format {
+        Format::Arrow => ipc::write(batch),
+        Format::Parquet => parquet::write(batch, row_group_size),
+    };
+
+    let path = Path::new(path);
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    let write = || -> std::io::Result<()> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        fs::write(&tmp, &bytes)?;
+        fs::rename(&tmp, path)
+    };
+
+    write().map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        WriteError::new(&path.to_string_lossy(), &e.to_string())
+    })
+}
+
+// where to write a table instead of returning it, from a character vector
+// with `path` and `format` passed in from R, or NULL. an optional
+// `row_group_size` caps the rows in each Parquet row group.
+pub(crate) struct Output {
+    path: String,
+    format: Format,
+    row_group_size: usize,
+}
+
+impl Output {
+    pub(crate) fn from_robj(x: &Robj) -> Option<Output> {
+        let x: HashMap<String, String> = named_strings(x).into_iter().collect();
+
+        Some(Output {
+            path: x.get("path")?.clone(),
+            format: Format::parse(x.get("format")?)?,
+            row_group_size: x
+                .get("row_group_size")
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(parquet::ROW_GROUP_SIZE),
+        })
+    }
+
+    // the path if the file was written, so R can return it, otherwise a
+    // condition
+    pub(crate) fn write(&self, batch: &RecordBatch) -> Robj {
+        match write_file(batch, &self.path, self.format, self.row_group_size) {
+            Ok(()) => self.path.clone().into_robj(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// TABLES ----------------------------------------------------------------------
+// the same tables `get_elements()`, `get_forecasts()` and `get_stations()`
+// return, flattened so every column has a plain Arrow type: station data are
+// in long format, forecasts have one row per forecast value, and station
+// metadata leave out the nested element, forecast and reservoir metadata
+// (except the forecast point and reservoir columns, which are one per
+// station). empty columns are kept, so every file has the same schema.
+
+// repeat each x[i] counts[i] times
+fn repeat<T: Clone>(x: &[T], counts: &[usize]) -> Vec<T> {
+    let mut out = Vec::with_capacity(counts.iter().sum());
+
+    for (v, &n) in x.iter().zip(counts.iter()) {
+        out.extend(std::iter::repeat(v).take(n).cloned());
+    }
+
+    out
+}
+
+fn strings<'a, I: Iterator<Item = &'a str>>(x: I) -> Vec<Option<String>> {
+    x.map(|s| Some(s.to_string())).collect()
+}
+
+// `station_id`, `state_code` and `network_code`, as with `triplet_columns`
+fn push_triplet_columns(batch: &mut RecordBatch, triplets: &[StationTriplet]) {
+    batch
+        .push_required(
+            "station_id",
+            Column::Utf8(strings(triplets.iter().map(|t| t.station_id.as_str()))),
+        )
+        .push_required(
+            "state_code",
+            Column::Dictionary(strings(triplets.iter().map(|t| t.state_code.as_str()))),
+        )
+        .push_required(
+            "network_code",
+            Column::Dictionary(strings(triplets.iter().map(|t| t.network_code.as_str()))),
+        );
+}
+
+// `huc2` through `huc12`, as with `huc_columns`
+fn push_huc_columns<'a, I>(batch: &mut RecordBatch, hucs: I)
+where
+    I: Iterator<Item = Option<&'a str>> + Clone,
+{
+    for (name, codes) in huc::huc_levels(hucs) {
+        batch.push(name, Column::Dictionary(codes));
+    }
+}
+
+impl StationDataSet {
+    pub(crate) fn record_batch(
+        &self,
+        triplet_columns: bool,
+        hucs: Option<&StationHucs>,
+    ) -> RecordBatch {
+        let counts: Vec<usize> = self.offsets.windows(2).map(|w| w[1] - w[0]).collect();
+        let se = &self.station_element;
+        let v = &self.values;
+
+        let triplets = repeat(&self.station_triplet, &counts);
+        let per_value = |x: Vec<Option<String>>| repeat(&x, &counts);
+
+        let mut batch = RecordBatch::default();
+
+        batch.push_required(
+            "station_triplet",
+            Column::Dictionary(triplets.iter().map(|t| Some(t.to_string())).collect()),
+        );
+
+        if triplet_columns {
+            push_triplet_columns(&mut batch, &triplets);
+        }
+
+        if let Some(hucs) = hucs {
+            let keys: Vec<String> = triplets.iter().map(|t| t.to_string()).collect();
+
+            push_huc_columns(&mut batch, keys.iter().map(|t| hucs.get(t)));
+        }
+
+        let begin_date: Vec<Option<f64>> = se
+            .iter()
+            .map(|e| dates::parse_date(&e.begin_date))
+            .collect();
+        let end_date: Vec<Option<f64>> =
+            se.iter().map(|e| dates::parse_date(&e.end_date)).collect();
+
+        // as in long format, the date column is a timestamp if any element is
+        // hourly, with coarser values falling at midnight UTC
+        let date = if se.iter().any(|e| e.is_hourly()) {
+            Column::timestamps(v.date.clone())
+        } else {
+            Column::dates(v.date.iter().map(|&d| dates::seconds_to_days(d)).collect())
+        };
+
+        batch
+            .push_required(
+                "element_code",
+                Column::Dictionary(per_value(strings(
+                    se.iter().map(|e| e.element_code.as_str()),
+                ))),
+            )
+            .push_required(
+                "ordinal",
+                Column::Int32(repeat(
+                    &se.iter().map(|e| Some(e.ordinal)).collect::<Vec<_>>(),
+                    &counts,
+                )),
+            )
+            .push(
+                "height_depth",
+                Column::Int32(repeat(
+                    &se.iter().map(|e| e.height_depth).collect::<Vec<_>>(),
+                    &counts,
+                )),
+            )
+            .push_required(
+                "duration_name",
+                Column::Dictionary(per_value(strings(
+                    se.iter().map(|e| e.duration_name.as_str()),
+                ))),
+            )
+            .push_required(
+                "data_precision",
+                Column::Int32(repeat(
+                    &se.iter()
+                        .map(|e| Some(e.data_precision))
+                        .collect::<Vec<_>>(),
+                    &counts,
+                )),
+            )
+            .push_required(
+                "stored_unit_code",
+                Column::Dictionary(per_value(strings(
+                    se.iter().map(|e| e.stored_unit_code.as_str()),
+                ))),
+            )
+            .push_required(
+                "original_unit_code",
+                Column::Dictionary(per_value(strings(
+                    se.iter().map(|e| e.original_unit_code.as_str()),
+                ))),
+            )
+            .push("begin_date", Column::dates(repeat(&begin_date, &counts)))
+            .push("end_date", Column::dates(repeat(&end_date, &counts)))
+            .push_required(
+                "derived_data",
+                Column::Boolean(repeat(
+                    &se.iter().map(|e| Some(e.derived_data)).collect::<Vec<_>>(),
+                    &counts,
+                )),
+            )
+            .push("date", date)
+            .push("month", Column::Int32(v.month.clone()))
+            .push("month_part", Column::Dictionary(v.month_part.clone()))
+            .push("year", Column::Int32(v.year.clone()))
+            .push(
+                "collection_date",
+                Column::dates(
+                    v.collection_date
+                        .iter()
+                        .map(|&d| dates::seconds_to_days(d))
+                        .collect(),
+                ),
+            )
+            .push("value", Column::Float64(v.value.clone()))
+            .push("qc_flag", Column::Dictionary(v.qc_flag.clone()))
+            .push("qa_flag", Column::Dictionary(v.qa_flag.clone()))
+            .push("orig_value", Column::Float64(v.orig_value.clone()))
+            .push("orig_qc_flag", Column::Dictionary(v.orig_qc_flag.clone()))
+            .push("average", Column::Float64(v.average.clone()))
+            .push("median", Column::Float64(v.median.clone()));
+
+        batch
+    }
+}
+
+impl StationForecastSet {
+    // a forecast without any values yet is kept as a single row, with a
+    // missing probability and value
+    pub(crate) fn record_batch(
+        &self,
+        triplet_columns: bool,
+        hucs: Option<&StationHucs>,
+        periods: &ForecastPeriods,
+    ) -> RecordBatch {
+        let mut triplets: Vec<StationTriplet> = Vec::new();
+        let mut forecast_point_name: Vec<Option<String>> = Vec::new();
+        let mut element_code: Vec<Option<String>> = Vec::new();
+        let mut forecast_period: Vec<Option<String>> = Vec::new();
+        let mut period_begin: Vec<Option<String>> = Vec::new();
+        let mut period_end: Vec<Option<String>> = Vec::new();
+        let mut period_begin_date: Vec<Option<f64>> = Vec::new();
+        let mut period_end_date: Vec<Option<f64>> = Vec::new();
+        let mut forecast_status: Vec<Option<String>> = Vec::new();
+        let mut issue_date: Vec<Option<f64>> = Vec::new();
+        let mut period_normal: Vec<Option<f64>> = Vec::new();
+        let mut publication_date: Vec<Option<f64>> = Vec::new();
+        let mut unit_code: Vec<Option<String>> = Vec::new();
+        let mut probability: Vec<Option<f64>> = Vec::new();
+        let mut value: Vec<Option<f64>> = Vec::new();
+
+        for x in self.0.iter() {
+            for y in x.data.iter() {
+                let fp = ForecastPeriod::new(&y.forecast_period, &y.issue_date, periods);
+                let (mut p, mut v) = parse_forecast_values(y.forecast_values.clone());
+
+                if p.is_empty() {
+                    p.push(None);
+                    v.push(None);
+                }
+
+                for _ in 0..p.len() {
+                    triplets.push(x.station_triplet.clone());
+                    forecast_point_name.push(Some(x.forecast_point_name.clone()));
+                    element_code.push(Some(y.element_code.clone()));
+                    forecast_period.push(fp.code.clone());
+                    period_begin.push(fp.begin.clone());
+                    period_end.push(fp.end.clone());
+                    period_begin_date.push(fp.begin_date);
+                    period_end_date.push(fp.end_date);
+                    forecast_status.push(Some(y.forecast_status.clone()));
+                    issue_date.push(dates::parse_date(&y.issue_date));
+                    period_normal.push(y.period_normal);
+                    publication_date.push(dates::parse_date(&y.publication_date));
+                    unit_code.push(Some(y.unit_code.clone()));
+                }
+
+                probability.append(&mut p);
+                value.append(&mut v);
+            }
+        }
+
+        let mut batch = RecordBatch::default();
+
+        batch.push_required(
+            "station_triplet",
+            Column::Dictionary(triplets.iter().map(|t| Some(t.to_string())).collect()),
+        );
+
+        if triplet_columns {
+            push_triplet_columns(&mut batch, &triplets);
+        }
+
+        if let Some(hucs) = hucs {
+            let keys: Vec<String> = triplets.iter().map(|t| t.to_string()).collect();
+
+            push_huc_columns(&mut batch, keys.iter().map(|t| hucs.get(t)));
+        }
+
+        batch
+            .push_required(
+                "forecast_point_name",
+                Column::Dictionary(forecast_point_name),
+            )
+            .push_required("element_code", Column::Dictionary(element_code))
+            .push("forecast_period", Column::Dictionary(forecast_period))
+            .push("period_begin", Column::Dictionary(period_begin))
+            .push("period_end", Column::Dictionary(period_end))
+            .push("period_begin_date", Column::dates(period_begin_date))
+            .push("period_end_date", Column::dates(period_end_date))
+            .push_required("forecast_status", Column::Dictionary(forecast_status))
+            .push("issue_date", Column::dates(issue_date))
+            .push("period_normal", Column::Float64(period_normal))
+            .push("publication_date", Column::dates(publication_date))
+            .push_required("unit_code", Column::Dictionary(unit_code))
+            .push("probability", Column::Float64(probability))
+            .push("value", Column::Float64(value));
+
+        batch
+    }
+}
+
+impl StationMetadataSet {
+    pub(crate) fn record_batch(&self, huc_columns: bool) -> RecordBatch {
+        let sm = &self.0;
+        let text = |f: fn(&StationMetadata) -> Option<&str>| -> Vec<Option<String>> {
+            sm.iter().map(|x| f(x).map(str::to_string)).collect()
+        };
+        let number = |f: fn(&StationMetadata) -> Option<f64>| -> Vec<Option<f64>> {
+            sm.iter().map(f).collect()
+        };
+        let date = |f: fn(&StationMetadata) -> Option<&str>| -> Column {
+            Column::dates(
+                sm.iter()
+                    .map(|x| f(x).and_then(dates::parse_date))
+                    .collect(),
+            )
+        };
+        let reservoir = |f: fn(&ReservoirMetadata) -> i32| -> Column {
+            Column::Int32(
+                sm.iter()
+                    .map(|x| x.reservoir_metadata.as_ref().map(f))
+                    .collect(),
+            )
+        };
+
+        let mut batch = RecordBatch::default();
+
+        batch
+            .push_required(
+                "station_triplet",
+                Column::Utf8(text(|x| Some(&x.station_triplet))),
+            )
+            .push_required("station_id", Column::Utf8(text(|x| Some(&x.station_id))))
+            .push_required(
+                "state_code",
+                Column::Dictionary(text(|x| Some(&x.state_code))),
+            )
+            .push_required(
+                "network_code",
+                Column::Dictionary(text(|x| Some(&x.network_code))),
+            )
+            .push("name", Column::Utf8(text(|x| x.name.as_deref())))
+            .push(
+                "dco_code",
+                Column::Dictionary(text(|x| x.dco_code.as_deref())),
+            )
+            .push(
+                "county_name",
+                Column::Dictionary(text(|x| x.county_name.as_deref())),
+            )
+            .push("huc", Column::Utf8(text(|x| x.huc.as_deref())));
+
+        if huc_columns {
+            push_huc_columns(&mut batch, sm.iter().map(|x| x.huc.as_deref()));
+        }
+
+        batch
+            .push("elevation", Column::Float64(number(|x| x.elevation)))
+            .push_required("latitude", Column::Float64(number(|x| Some(x.latitude))))
+            .push_required("longitude", Column::Float64(number(|x| Some(x.longitude))))
+            .push(
+                "data_time_zone",
+                Column::Float64(number(|x| x.data_time_zone)),
+            )
+            .push(
+                "pedon_code",
+                Column::Utf8(text(|x| x.pedon_code.as_deref())),
+            )
+            .push("shef_id", Column::Utf8(text(|x| x.shef_id.as_deref())))
+            .push("begin_date", date(|x| x.begin_date.as_deref()))
+            .push("end_date", date(|x| x.end_date.as_deref()))
+            .push(
+                "forecast_point_name",
+                Column::Utf8(text(|x| x.forecast_point.as_ref().map(|f| f.name.as_str()))),
+            )
+            .push(
+                "forecaster",
+                Column::Dictionary(text(|x| {
+                    x.forecast_point.as_ref().map(|f| f.forecaster.as_str())
+                })),
+            )
+            .push("capacity", reservoir(|r| r.capacity))
+            .push(
+                "elevation_at_capacity",
+                reservoir(|r| r.elevation_at_capacity),
+            )
+            .push("usable_capacity", reservoir(|r| r.usable_capacity));
+
+        batch
+    }
+}
@@ -0,0 +1,366 @@
+use extendr_api::prelude::*;
+
+use crate::dates;
+use crate::element::ElementTriplet;
+
+// BATCHES ---------------------------------------------------------------------
+// AWDB caps a request at roughly 1000 elements, counting every element of
+// every station in it, not the number of stations. the element metadata from
+// the stations endpoint list the elements each station has, so stations can be
+// packed into requests by how many elements they will return. hourly data are
+// also packed by the number of values, since a long span of hourly values
+// makes for a very large response even with few elements.
+const MAX_ELEMENTS: f64 = 1000.0;
+const MAX_HOURLY_VALUES: f64 = 500_000.0;
+
+const HOURS_PER_DAY: f64 = 24.0;
+
+// long hourly and daily pulls are also split into consecutive date windows of
+// at most this many days, each requested on its own
+const HOURLY_WINDOW_DAYS: f64 = 365.0;
+const DAILY_WINDOW_DAYS: f64 = 3650.0;
+
+// a date window, in days since the epoch, where None leaves the date out of
+// the request
+type Window = (Option<f64>, Option<f64>);
+
+// one row of a station's element metadata
+struct Series {
+    element_code: String,
+    height_depth: Option<i32>,
+    ordinal: Option<i32>,
+    duration_name: Option<String>,
+    begin_date: Option<f64>,
+    end_date: Option<f64>,
+}
+
+// what a request for the data endpoint asks of each station
+pub(crate) struct DataQuery<'a> {
+    pub(crate) elements: Vec<ElementTriplet>,
+    pub(crate) duration: Option<&'a str>,
+    pub(crate) begin_date: Option<f64>,
+    pub(crate) end_date: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Cost {
+    elements: f64,
+    values: f64,
+}
+
+impl Cost {
+    fn max(self, other: Cost) -> Cost {
+        Cost {
+            elements: self.elements.max(other.elements),
+            values: self.values.max(other.values),
+        }
+    }
+}
+
+impl DataQuery<'_> {
+    fn is_hourly(&self) -> bool {
+        self.duration
+            .map_or(false, |d| d.eq_ignore_ascii_case("hourly"))
+    }
+
+    fn wants(&self, s: &Series) -> bool {
+        let duration = match (self.duration, &s.duration_name) {
+            (Some(d), Some(name)) => d.eq_ignore_ascii_case(name),
+            _ => true,
+        };
+
+        duration
+            && self
+                .elements
+                .iter()
+                .any(|e| e.matches(&s.element_code, s.height_depth, s.ordinal))
+    }
+
+    fn window_days(&self) -> Option<f64> {
+        match self.duration?.to_ascii_uppercase().as_str() {
+            "HOURLY" => Some(HOURLY_WINDOW_DAYS),
+            "DAILY" => Some(DAILY_WINDOW_DAYS),
+            _ => None,
+        }
+    }
+
+    // consecutive windows that don't overlap and together cover the query's
+    // dates. without a begin date there's nothing to split, and without an end
+    // date the last window is left open so AWDB still returns through today.
+    fn windows(&self, today: f64) -> Vec<Window> {
+        let whole = vec![(self.begin_date, self.end_date)];
+
+        let (Some(days), Some(begin)) = (self.window_days(), self.begin_date) else {
+            return whole;
+        };
+
+        let end = self.end_date.unwrap_or(today);
+
+        if end - begin + 1.0 <= days {
+            return whole;
+        }
+
+        let mut windows = Vec::new();
+        let mut start = begin;
+
+        while start <= end {
+            let stop = start + days - 1.0;
+
+            if stop >= end {
+                windows.push((Some(start), self.end_date));
+                break;
+            }
+
+            windows.push((Some(start), Some(stop)));
+            start = stop + 1.0;
+        }
+
+        windows
+    }
+
+    // hourly values in the part of the window the series covers. stations
+    // still reporting have an end date far in the future, so that is capped at
+    // today.
+    fn hourly_values(&self, s: &Series, window: Window, today: f64) -> f64 {
+        let begin = match (window.0, s.begin_date) {
+            (Some(a), Some(b)) => a.max(b),
+            (a, b) => a.or(b).unwrap_or(today),
+        };
+
+        let end = match (window.1, s.end_date) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(today),
+        }
+        .min(today);
+
+        ((end - begin + 1.0) * HOURS_PER_DAY).max(0.0)
+    }
+
+    fn cost(&self, series: &[Series], window: Window, today: f64) -> Cost {
+        let mut cost = Cost::default();
+
+        for s in series.iter().filter(|s| self.wants(s)) {
+            cost.elements += 1.0;
+
+            if self.is_hourly() {
+                cost.values += self.hourly_values(s, window, today);
+            }
+        }
+
+        // filter_stations() only keeps stations with the requested elements,
+        // so a station with no match still gets counted once
+        cost.elements = cost.elements.max(1.0);
+
+        cost
+    }
+}
+
+// pack stations in order into batches, starting a new batch whenever the next
+// station would go over either limit. a station over the limits by itself gets
+// a batch of its own.
+fn pack(costs: &[Cost]) -> Vec<std::ops::Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut total = Cost::default();
+
+    for (i, cost) in costs.iter().enumerate() {
+        let over = total.elements + cost.elements > MAX_ELEMENTS
+            || total.values + cost.values > MAX_HOURLY_VALUES;
+
+        if over && i > start {
+            batches.push(start..i);
+            start = i;
+            total = Cost::default();
+        }
+
+        total.elements += cost.elements;
+        total.values += cost.values;
+    }
+
+    if start < costs.len() {
+        batches.push(start..costs.len());
+    }
+
+    batches
+}
+
+// one request: comma-separated station triplets, and the date window to ask
+// for in place of the query's own dates, if the query was split
+pub(crate) struct Batch {
+    pub(crate) stations: String,
+    pub(crate) begin_date: Option<String>,
+    pub(crate) end_date: Option<String>,
+}
+
+// the batches for a query. stations are packed by what they cost in their
+// most expensive window, and each batch is then requested once per window, so
+// a station's windows are requested one after another and in date order.
+// stations without element metadata count as a `request_size` share of the
+// limits, so `request_size` of them fill a request. `today`, in days since the
+// epoch, closes open-ended windows and series.
+pub(crate) fn batch_station_triplets(
+    station_triplets: &[String],
+    element_metadata: &[Option<Robj>],
+    query: &DataQuery,
+    request_size: usize,
+    split_dates: bool,
+    today: f64,
+) -> Vec<Batch> {
+    let share = request_size.max(1) as f64;
+    let fallback = Cost {
+        elements: MAX_ELEMENTS / share,
+        values: if query.is_hourly() {
+            MAX_HOURLY_VALUES / share
+        } else {
+            0.0
+        },
+    };
+
+    let windows = if split_dates {
+        query.windows(today)
+    } else {
+        vec![(query.begin_date, query.end_date)]
+    };
+
+    let costs: Vec<Cost> = (0..station_triplets.len())
+        .map(|i| match element_metadata.get(i).and_then(|x| x.as_ref()) {
+            Some(df) => {
+                let series = series(df);
+
+                windows
+                    .iter()
+                    .map(|w| query.cost(&series, *w, today))
+                    .fold(Cost::default(), Cost::max)
+            }
+            None => fallback,
+        })
+        .collect();
+
+    // a single window is just the query's own dates. hourly windows are sent
+    // with explicit times, from 00:00 on their first day to 23:59 on their
+    // last, so each window holds every hour of its days whatever time AWDB
+    // would give a bare date, and no two windows share an hour.
+    let hourly = query.is_hourly();
+    let format = |x: Option<f64>, time: &str| {
+        x.filter(|_| windows.len() > 1).map(|d| {
+            let date = dates::format_date(d as i64);
+
+            if hourly {
+                format!("{date} {time}")
+            } else {
+                date
+            }
+        })
+    };
+
+    let mut batches = Vec::new();
+
+    for batch in pack(&costs) {
+        let stations = station_triplets[batch].join(",");
+
+        for (begin, end) in windows.iter() {
+            batches.push(Batch {
+                stations: stations.clone(),
+                begin_date: format(*begin, "00:00"),
+                end_date: format(*end, "23:59"),
+            });
+        }
+    }
+
+    batches
+}
+
+// batches go to R as a data frame with NA for dates left to the query
+pub(crate) fn batches_robj(batches: &[Batch]) -> Robj {
+    let mut df = data_frame!(
+        station_triplets = batches
+            .iter()
+            .map(|b| b.stations.as_str())
+            .collect::<Vec<_>>(),
+        begin_date = batches
+            .iter()
+            .map(|b| b.begin_date.clone())
+            .collect::<Vec<_>>(),
+        end_date = batches
+            .iter()
+            .map(|b| b.end_date.clone())
+            .collect::<Vec<_>>()
+    );
+
+    df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+    df
+}
+
+// and come back the same way
+pub(crate) fn batches_from_robj(x: &Robj) -> Vec<Batch> {
+    let Some(columns) = x.as_list() else {
+        return Vec::new();
+    };
+
+    let column = |name: &str| -> Vec<Option<String>> {
+        columns
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, x)| crate::strings(&x))
+            .unwrap_or_default()
+    };
+
+    let begin_date = column("begin_date");
+    let end_date = column("end_date");
+
+    column("station_triplets")
+        .into_iter()
+        .enumerate()
+        .map(|(i, stations)| Batch {
+            stations: stations.unwrap_or_default(),
+            begin_date: begin_date.get(i).cloned().flatten(),
+            end_date: end_date.get(i).cloned().flatten(),
+        })
+        .collect()
+}
+
+// read the element metadata table built by `station_elements_robj()`. columns
+// that were dropped for being empty read as missing.
+fn series(df: &Robj) -> Vec<Series> {
+    let Some(columns) = df.as_list() else {
+        return Vec::new();
+    };
+
+    let column = |name: &str| columns.iter().find(|(n, _)| *n == name).map(|(_, x)| x);
+
+    let Some(codes) = column("element_code").and_then(|x| x.as_string_vector()) else {
+        return Vec::new();
+    };
+
+    let integers = |name: &str| {
+        column(name)
+            .map(|x| crate::integers(&x))
+            .unwrap_or_default()
+    };
+    let doubles = |name: &str| column(name).map(|x| crate::doubles(&x)).unwrap_or_default();
+
+    let height_depth = integers("height_depth");
+    let ordinal = integers("ordinal");
+    let begin_date = doubles("begin_date");
+    let end_date = doubles("end_date");
+    let duration_name = column("duration_name").and_then(|x| x.as_string_vector());
+
+    codes
+        .into_iter()
+        .enumerate()
+        .map(|(i, element_code)| Series {
+            element_code,
+            height_depth: height_depth.get(i).copied().flatten(),
+            ordinal: ordinal.get(i).copied().flatten(),
+            duration_name: duration_name.as_ref().and_then(|d| d.get(i).cloned()),
+            begin_date: begin_date.get(i).copied().flatten(),
+            end_date: end_date.get(i).copied().flatten(),
+        })
+        .collect()
+}
+
+// "YYYY-MM-DD" from R, or NULL
+pub(crate) fn query_date(x: &Robj) -> Option<f64> {
+    x.as_str().and_then(dates::parse_date)
+}
@@ -0,0 +1,255 @@
+use extendr_api::prelude::*;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dates;
+use crate::fetch::Request;
+
+// CACHE -----------------------------------------------------------------------
+// raw JSON bodies are kept on disk, one file per request, named by a hash of
+// the request's key: the base url, endpoint and query, with the query sorted
+// so the order parameters are given in doesn't matter. each file starts with
+// two header lines, the key itself (checked on read, so a hash collision is a
+// miss) and the time the entry expires, followed by the body.
+//
+//   <dir>/<endpoint>/<hash>.json
+//
+// the cache is best effort: anything that goes wrong reading or writing it is
+// treated as a miss.
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
+// data ending this close to today may still be revised or filled in
+const RECENT_DAYS: f64 = 7.0;
+
+const EXTENSION: &str = "json";
+
+pub(crate) struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    // from R: NULL for no cache, or the path to the cache directory
+    pub(crate) fn from_robj(x: &Robj) -> Option<Self> {
+        x.as_str().map(|dir| Cache {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    // a fresh body for the request, if there is one
+    pub(crate) fn get(&self, request: &Request) -> Option<String> {
+        let key = request.key();
+        let text = fs::read_to_string(self.path(request.endpoint, &key)).ok()?;
+
+        let (header, body) = split_header(&text)?;
+
+        if header.key != key || header.expires <= now() {
+            return None;
+        }
+
+        Some(body.to_string())
+    }
+
+    pub(crate) fn put(&self, request: &Request, body: &str) {
+        let key = request.key();
+        let path = self.path(request.endpoint, &key);
+        let expires = now() + ttl(request);
+
+        // write to a temporary file first and move it into place, so other
+        // threads and sessions never see half an entry
+        let write = || -> std::io::Result<()> {
+            let dir = path.parent().unwrap_or(&self.dir);
+            fs::create_dir_all(dir)?;
+
+            let tmp = dir.join(format!(
+                "{}.{}.{:?}.tmp",
+                hash(&key),
+                std::process::id(),
+                std::thread::current().id()
+            ));
+
+            let mut file = fs::File::create(&tmp)?;
+            writeln!(file, "{key}")?;
+            writeln!(file, "{expires}")?;
+            file.write_all(body.as_bytes())?;
+            drop(file);
+
+            fs::rename(&tmp, &path)
+        };
+
+        let _ = write();
+    }
+
+    fn path(&self, endpoint: &str, key: &str) -> PathBuf {
+        self.dir
+            .join(endpoint)
+            .join(format!("{}.{EXTENSION}", hash(key)))
+    }
+
+    // every entry in the cache, skipping anything that isn't one
+    pub(crate) fn entries(&self) -> Vec<Entry> {
+        let Ok(endpoints) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+
+        let mut entries = Vec::new();
+
+        for endpoint in endpoints.flatten() {
+            let Ok(files) = fs::read_dir(endpoint.path()) else {
+                continue;
+            };
+
+            for file in files.flatten() {
+                if let Some(entry) = Entry::read(&file.path()) {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        entries
+    }
+}
+
+struct Header<'a> {
+    key: &'a str,
+    expires: u64,
+}
+
+fn split_header(text: &str) -> Option<(Header<'_>, &str)> {
+    let (key, rest) = text.split_once('\n')?;
+    let (expires, body) = rest.split_once('\n')?;
+
+    let header = Header {
+        key,
+        expires: expires.parse().ok()?,
+    };
+
+    Some((header, body))
+}
+
+// 64-bit FNV-1a, which is plenty for file names since keys are checked on read
+fn hash(x: &str) -> String {
+    let mut h: u64 = 0xcbf29ce484222325;
+
+    for b in x.bytes() {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{h:016x}")
+}
+
+// how long a response stays fresh: reference lists rarely change, station
+// metadata and forecasts change daily at most, and data ending recently may
+// still be revised
+fn ttl(request: &Request) -> u64 {
+    match request.endpoint {
+        "reference-data" => 7 * DAY,
+        "stations" => DAY,
+        "forecasts" => 6 * HOUR,
+        "data" if ends_recently(request) => HOUR,
+        "data" => 7 * DAY,
+        _ => HOUR,
+    }
+}
+
+// AWDB returns data through today when there's no end date
+fn ends_recently(request: &Request) -> bool {
+    let end_date = request
+        .query
+        .iter()
+        .find(|(k, _)| k == "endDate")
+        .and_then(|(_, v)| dates::parse_date(v));
+
+    let today = (now() / DAY) as f64;
+
+    end_date.map_or(true, |d| d >= today - RECENT_DAYS)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// CACHE ENTRIES ---------------------------------------------------------------
+// what `awdb_cache_info()` shows for each cached response
+pub(crate) struct Entry {
+    path: PathBuf,
+    endpoint: String,
+    key: String,
+    size: u64,
+    created: Option<u64>,
+    expires: u64,
+}
+
+impl Entry {
+    // only the header is read, and only files named like cache entries count
+    fn read(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let stem = name.strip_suffix(&format!(".{EXTENSION}"))?;
+
+        if stem.len() != 16 || !stem.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let file = fs::File::open(path).ok()?;
+        let metadata = file.metadata().ok()?;
+        let mut lines = BufReader::new(file).lines();
+
+        let key = lines.next()?.ok()?;
+        let expires = lines.next()?.ok()?.parse().ok()?;
+
+        let created = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        Some(Entry {
+            path: path.to_path_buf(),
+            endpoint: path.parent()?.file_name()?.to_str()?.to_string(),
+            key,
+            size: metadata.len(),
+            created,
+            expires,
+        })
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+
+    pub(crate) fn remove(&self) -> bool {
+        fs::remove_file(&self.path).is_ok()
+    }
+}
+
+pub(crate) fn entries_robj(entries: &[Entry]) -> Robj {
+    let seconds = |x: Option<u64>| x.map(|s| s as f64);
+
+    let mut df = data_frame!(
+        endpoint = entries
+            .iter()
+            .map(|e| e.endpoint.as_str())
+            .collect::<Vec<_>>(),
+        key = entries.iter().map(|e| e.key.as_str()).collect::<Vec<_>>(),
+        size = entries.iter().map(|e| e.size as f64).collect::<Vec<_>>(),
+        created = dates::datetime_robj(entries.iter().map(|e| seconds(e.created)).collect()),
+        expires = dates::datetime_robj(entries.iter().map(|e| seconds(Some(e.expires))).collect()),
+        expired = entries.iter().map(|e| e.is_expired()).collect::<Vec<_>>(),
+        path = entries
+            .iter()
+            .map(|e| e.path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+    );
+
+    df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+    df
+}
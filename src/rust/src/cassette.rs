@@ -0,0 +1,198 @@
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::dates;
+use crate::errors::RequestError;
+use crate::fetch::Request;
+use crate::http::Response;
+
+// CASSETTES -------------------------------------------------------------------
+// a cassette is a JSON file of request and response pairs. recording saves the
+// status and body of every response that comes back, keyed like the cache by
+// the request with its query sorted, and replaying serves them without going
+// near the network. a request that isn't on the cassette is an error when
+// replaying, so a replayed run either matches the recorded one or fails.
+//
+// the file is sorted by request so that re-recording the same run gives the
+// same file. it also holds the date it was first recorded on, which stands in
+// for today while the cassette is in use, so a query without an end date is
+// split into the same date windows and batches, and so the same requests, on
+// replay as it was when recorded.
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Record,
+    Replay,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    request: String,
+    status: u32,
+    body: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CassetteFile {
+    version: u32,
+    // "YYYY-MM-DD", missing from cassettes recorded before it was added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    today: Option<String>,
+    interactions: Vec<Interaction>,
+}
+
+pub(crate) struct Cassette {
+    path: PathBuf,
+    mode: Mode,
+    today: Option<f64>,
+    interactions: Mutex<Interactions>,
+}
+
+impl Cassette {
+    // from R: NULL for no cassette, or c(path = , mode = ) with mode "record"
+    // or "replay". recording adds to an existing cassette, and replaying
+    // needs one.
+    pub(crate) fn from_robj(x: &Robj) -> std::result::Result<Option<Self>, RequestError> {
+        let (Some(names), Some(values)) = (x.names(), x.as_str_iter()) else {
+            return Ok(None);
+        };
+
+        let fields: BTreeMap<&str, &str> = names.zip(values).collect();
+
+        let (Some(path), Some(mode)) = (fields.get("path"), fields.get("mode")) else {
+            return Ok(None);
+        };
+
+        let mode = match *mode {
+            "record" => Mode::Record,
+            "replay" => Mode::Replay,
+            other => {
+                return Err(RequestError::cassette(
+                    path,
+                    &format!("unknown cassette mode `{other}`"),
+                ))
+            }
+        };
+
+        let path = PathBuf::from(path);
+
+        let (today, interactions) = match (fs::read_to_string(&path), mode) {
+            (Ok(text), _) => read(&text).map_err(|e| {
+                RequestError::cassette(
+                    &path.to_string_lossy(),
+                    &format!("can't read cassette: {e}"),
+                )
+            })?,
+            (Err(_), Mode::Record) => (None, BTreeMap::new()),
+            (Err(e), Mode::Replay) => {
+                return Err(RequestError::cassette(
+                    &path.to_string_lossy(),
+                    &format!("can't open cassette: {e}"),
+                ))
+            }
+        };
+
+        Ok(Some(Cassette {
+            path,
+            mode,
+            today,
+            interactions: Mutex::new(interactions),
+        }))
+    }
+
+    pub(crate) fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // the date the cassette was first recorded on, in days since the epoch
+    pub(crate) fn today(&self) -> Option<f64> {
+        self.today
+    }
+
+    pub(crate) fn record(&self, request: &Request, response: &Response) {
+        let mut interactions = self.interactions.lock().unwrap();
+
+        interactions.insert(request.key(), (response.status, response.body.clone()));
+    }
+
+    pub(crate) fn replay(&self, request: &Request) -> std::result::Result<Response, RequestError> {
+        let key = request.key();
+        let interactions = self.interactions.lock().unwrap();
+
+        match interactions.get(&key) {
+            Some((status, body)) => Ok(Response {
+                status: *status,
+                body: body.clone(),
+            }),
+            None => Err(RequestError::cassette(
+                &request.url(),
+                &format!("request not recorded in cassette `{}`", self.path.display()),
+            )),
+        }
+    }
+
+    // write out everything recorded so far, keeping what was already there
+    pub(crate) fn save(&self) -> std::result::Result<(), RequestError> {
+        if self.mode != Mode::Record {
+            return Ok(());
+        }
+
+        let interactions = self.interactions.lock().unwrap();
+
+        let file = CassetteFile {
+            version: VERSION,
+            today: Some(dates::format_date(
+                self.today.unwrap_or_else(dates::today) as i64
+            )),
+            interactions: interactions
+                .iter()
+                .map(|(request, (status, body))| Interaction {
+                    request: request.clone(),
+                    status: *status,
+                    body: body.clone(),
+                })
+                .collect(),
+        };
+
+        let path = self.path.to_string_lossy();
+        let error = |e: &dyn std::fmt::Display| {
+            RequestError::cassette(&path, &format!("can't write cassette: {e}"))
+        };
+
+        let text = serde_json::to_string_pretty(&file).map_err(|e| error(&e))?;
+
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| error(&e))?;
+        }
+
+        fs::write(&self.path, text).map_err(|e| error(&e))
+    }
+}
+
+type Interactions = BTreeMap<String, (u32, String)>;
+
+fn read(text: &str) -> std::result::Result<(Option<f64>, Interactions), String> {
+    let file: CassetteFile = serde_json::from_str(text).map_err(|e| e.to_string())?;
+
+    if file.version != VERSION {
+        return Err(format!("unsupported cassette version {}", file.version));
+    }
+
+    let today = match file.today {
+        Some(x) => Some(dates::parse_date(&x).ok_or(format!("invalid date `{x}`"))?),
+        None => None,
+    };
+
+    let interactions = file
+        .interactions
+        .into_iter()
+        .map(|i| (i.request, (i.status, i.body)))
+        .collect();
+
+    Ok((today, interactions))
+}
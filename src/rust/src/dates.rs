@@ -0,0 +1,197 @@
+use extendr_api::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// DATES -----------------------------------------------------------------------
+// AWDB sends dates as "YYYY-MM-DD" or "YYYY-MM-DD HH:MM", always in the
+// station's local standard time. These are parsed here into the numbers R uses
+// for Date (days since 1970-01-01) and POSIXct (seconds since 1970-01-01 UTC).
+
+pub(crate) const SECONDS_PER_DAY: f64 = 86400.0;
+
+// days since 1970-01-01 in the proleptic gregorian calendar, after
+// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+pub(crate) fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year } as i64;
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+// the inverse of `days_from_civil()`, after
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub(crate) fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year as i32, month as u32, day as u32)
+}
+
+// as "YYYY-MM-DD"
+pub(crate) fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+// days since the epoch, in UTC
+pub(crate) fn today() -> f64 {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    (seconds as f64 / SECONDS_PER_DAY).floor()
+}
+
+pub(crate) fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
+
+fn digits(x: &[u8]) -> Option<u32> {
+    if x.is_empty() || !x.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    Some(x.iter().fold(0, |acc, d| acc * 10 + (d - b'0') as u32))
+}
+
+// split "YYYY-MM-DD[ HH:MM[:SS]]" into its parts, checking that each is valid
+fn parse_parts(x: &str) -> Option<(i32, u32, u32, u32)> {
+    let b = x.trim().as_bytes();
+
+    if b.len() < 10 || b[4] != b'-' || b[7] != b'-' {
+        return None;
+    }
+
+    let year = digits(&b[0..4])? as i32;
+    let month = digits(&b[5..7])?;
+    let day = digits(&b[8..10])?;
+
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let mut seconds = 0;
+
+    if b.len() > 10 {
+        if !(b[10] == b' ' || b[10] == b'T') || b.len() < 16 || b[13] != b':' {
+            return None;
+        }
+
+        let hour = digits(&b[11..13])?;
+        let minute = digits(&b[14..16])?;
+        let second = if b.len() >= 19 && b[16] == b':' {
+            digits(&b[17..19])?
+        } else {
+            0
+        };
+
+        if hour > 24 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        seconds = hour * 3600 + minute * 60 + second;
+    }
+
+    Some((year, month, day, seconds))
+}
+
+// "MM-DD" as used for forecast periods, returning (month, day)
+pub(crate) fn parse_month_day(x: &str) -> Option<(u32, u32)> {
+    let b = x.trim().as_bytes();
+
+    if b.len() != 5 || b[2] != b'-' {
+        return None;
+    }
+
+    let month = digits(&b[0..2])?;
+    let day = digits(&b[3..5])?;
+
+    // check against a leap year so that 02-29 is allowed
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(2000, month) {
+        return None;
+    }
+
+    Some((month, day))
+}
+
+// water years run October through September and are named for the calendar
+// year they end in, so 2023-10-01 is in water year 2024
+pub(crate) fn water_year(x: &str) -> Option<i32> {
+    let (year, month, _, _) = parse_parts(x)?;
+
+    Some(if month >= 10 { year + 1 } else { year })
+}
+
+// days since the epoch for a month-day falling in the given water year, with
+// Feb 29 moved to Feb 28 in years that don't have one
+pub(crate) fn water_year_date(water_year: i32, month: u32, day: u32) -> f64 {
+    let year = if month >= 10 {
+        water_year - 1
+    } else {
+        water_year
+    };
+    let day = day.min(days_in_month(year, month));
+
+    days_from_civil(year, month, day) as f64
+}
+
+// days since the epoch, ignoring any time of day
+pub(crate) fn parse_date(x: &str) -> Option<f64> {
+    let (year, month, day, _) = parse_parts(x)?;
+
+    Some(days_from_civil(year, month, day) as f64)
+}
+
+// seconds since the epoch, with no time zone adjustment
+pub(crate) fn parse_datetime(x: &str) -> Option<f64> {
+    let (year, month, day, seconds) = parse_parts(x)?;
+
+    Some(days_from_civil(year, month, day) as f64 * SECONDS_PER_DAY + seconds as f64)
+}
+
+pub(crate) fn seconds_to_days(x: Option<f64>) -> Option<f64> {
+    x.map(|s| (s / SECONDS_PER_DAY).floor())
+}
+
+// R Date vector from days since the epoch
+pub(crate) fn date_robj(x: Vec<Option<f64>>) -> Robj {
+    let mut robj = x.into_robj();
+
+    robj.set_class(&["Date"]).unwrap();
+
+    robj
+}
+
+// R POSIXct vector in UTC from seconds since the epoch
+pub(crate) fn datetime_robj(x: Vec<Option<f64>>) -> Robj {
+    let mut robj = x.into_robj();
+
+    robj.set_class(&["POSIXct", "POSIXt"]).unwrap();
+    robj.set_attrib("tzone", "UTC").unwrap();
+
+    robj
+}
+
+// convenience for the many string date fields in the AWDB responses
+pub(crate) fn dates_robj<'a, I>(x: I) -> Robj
+where
+    I: IntoIterator<Item = Option<&'a str>>,
+{
+    date_robj(x.into_iter().map(|d| d.and_then(parse_date)).collect())
+}
@@ -0,0 +1,240 @@
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+
+use crate::triplet::StationTriplet;
+use crate::{StationDataSet, StationElement, ValueColumns, Values};
+
+// STREAMING DESERIALIZATION ---------------------------------------------------
+// the /data endpoint returns [{stationTriplet, data: [{stationElement,
+// values: [...]}]}]. rather than build that nesting in memory and then copy
+// it into columns, these seeds walk the json and push each value into the
+// columns of a StationDataSet as soon as it is read.
+impl<'de> Deserialize<'de> for StationDataSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut sd = StationDataSet::default();
+
+        deserializer.deserialize_seq(StationDataSetVisitor(&mut sd))?;
+
+        Ok(sd)
+    }
+}
+
+struct StationDataSetVisitor<'a>(&'a mut StationDataSet);
+
+impl<'de> Visitor<'de> for StationDataSetVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of station data")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while seq.next_element_seed(StationDataSeed(self.0))?.is_some() {}
+
+        Ok(())
+    }
+}
+
+// STATION DATA ----------------------------------------------------------------
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "camelCase")]
+enum StationDataField {
+    StationTriplet,
+    Data,
+    #[serde(other)]
+    Other,
+}
+
+struct StationDataSeed<'a>(&'a mut StationDataSet);
+
+impl<'de> DeserializeSeed<'de> for StationDataSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct("StationData", &["stationTriplet", "data"], self)
+    }
+}
+
+impl<'de> Visitor<'de> for StationDataSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("struct StationData")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let first = self.0.station_element.len();
+        let mut station_triplet: Option<StationTriplet> = None;
+        let mut has_data = false;
+
+        while let Some(key) = map.next_key()? {
+            match key {
+                StationDataField::StationTriplet => station_triplet = Some(map.next_value()?),
+                StationDataField::Data => {
+                    map.next_value_seed(ElementDataSeqSeed(self.0))?;
+                    has_data = true;
+                }
+                StationDataField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        let station_triplet =
+            station_triplet.ok_or_else(|| de::Error::missing_field("stationTriplet"))?;
+
+        if !has_data {
+            return Err(de::Error::missing_field("data"));
+        }
+
+        // the triplet can come before or after its data, so fill it in for
+        // every station element that was pushed while reading this station
+        let n = self.0.station_element.len() - first;
+
+        self.0
+            .station_triplet
+            .extend(std::iter::repeat(station_triplet).take(n));
+
+        Ok(())
+    }
+}
+
+// ELEMENT DATA ----------------------------------------------------------------
+struct ElementDataSeqSeed<'a>(&'a mut StationDataSet);
+
+impl<'de> DeserializeSeed<'de> for ElementDataSeqSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ElementDataSeqSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of element data")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while seq.next_element_seed(ElementDataSeed(self.0))?.is_some() {}
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "camelCase")]
+enum ElementDataField {
+    StationElement,
+    Values,
+    #[serde(other)]
+    Other,
+}
+
+struct ElementDataSeed<'a>(&'a mut StationDataSet);
+
+impl<'de> DeserializeSeed<'de> for ElementDataSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_struct("ElementData", &["stationElement", "values"], self)
+    }
+}
+
+impl<'de> Visitor<'de> for ElementDataSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("struct ElementData")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut station_element: Option<StationElement> = None;
+        let mut has_values = false;
+
+        while let Some(key) = map.next_key()? {
+            match key {
+                ElementDataField::StationElement => station_element = Some(map.next_value()?),
+                ElementDataField::Values => {
+                    map.next_value_seed(ValuesSeqSeed(&mut self.0.values))?;
+                    has_values = true;
+                }
+                ElementDataField::Other => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        let station_element =
+            station_element.ok_or_else(|| de::Error::missing_field("stationElement"))?;
+
+        if !has_values {
+            return Err(de::Error::missing_field("values"));
+        }
+
+        self.0.station_element.push(station_element);
+        self.0.offsets.push(self.0.values.len());
+
+        Ok(())
+    }
+}
+
+// VALUES ----------------------------------------------------------------------
+struct ValuesSeqSeed<'a>(&'a mut ValueColumns);
+
+impl<'de> DeserializeSeed<'de> for ValuesSeqSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ValuesSeqSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence of values")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while let Some(v) = seq.next_element::<Values>()? {
+            self.0.push(v);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::ElementError;
+
+// ELEMENT TRIPLETS ------------------------------------------------------------
+// elements are selected with `elementCode:heightDepth:ordinal`, e.g. "SMS:-8",
+// "SMS:*" or "WTEQ::2". height depth and ordinal are optional, and any part
+// can be the `*` wildcard.
+const WILDCARD: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part<T> {
+    Any,
+    Value(T),
+}
+
+impl<T: FromStr> Part<T> {
+    fn parse(x: &str) -> Option<Self> {
+        if x == WILDCARD {
+            Some(Part::Any)
+        } else {
+            x.parse().ok().map(Part::Value)
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Part<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Part::Any => f.write_str(WILDCARD),
+            Part::Value(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ElementTriplet {
+    element_code: Part<String>,
+    height_depth: Option<Part<i32>>,
+    ordinal: Option<Part<u32>>,
+}
+
+impl FromStr for ElementTriplet {
+    type Err = ElementError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split(':').map(str::trim).collect();
+
+        if parts.len() > 3 {
+            return Err(ElementError::new(
+                s,
+                "expected at most three parts of the form `elementCode:heightDepth:ordinal`",
+            ));
+        }
+
+        // element codes are always upper case in AWDB
+        let element_code = parts[0].to_ascii_uppercase();
+
+        if element_code.is_empty() {
+            return Err(ElementError::new(s, "element code is missing"));
+        }
+
+        if element_code != WILDCARD && !element_code.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(ElementError::new(
+                s,
+                "element code must be letters, numbers, or `*`",
+            ));
+        }
+
+        let element_code = if element_code == WILDCARD {
+            Part::Any
+        } else {
+            Part::Value(element_code)
+        };
+
+        // an empty part is the same as leaving it off, so "WTEQ::2" skips
+        // straight to the ordinal
+        let height_depth = match parts.get(1) {
+            None | Some(&"") => None,
+            Some(x) => Some(Part::parse(x).ok_or_else(|| {
+                ElementError::new(s, "height depth must be a whole number of inches or `*`")
+            })?),
+        };
+
+        let ordinal = match parts.get(2) {
+            None | Some(&"") => None,
+            Some(x) => match Part::parse(x) {
+                Some(Part::Value(0)) | None => {
+                    return Err(ElementError::new(
+                        s,
+                        "ordinal must be a positive whole number or `*`",
+                    ))
+                }
+                ordinal => ordinal,
+            },
+        };
+
+        Ok(ElementTriplet {
+            element_code,
+            height_depth,
+            ordinal,
+        })
+    }
+}
+
+impl fmt::Display for ElementTriplet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.element_code)?;
+
+        match (&self.height_depth, &self.ordinal) {
+            (Some(h), Some(o)) => write!(f, ":{h}:{o}"),
+            (Some(h), None) => write!(f, ":{h}"),
+            (None, Some(o)) => write!(f, "::{o}"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl ElementTriplet {
+    // whether a series from a station's element metadata is one this triplet
+    // selects. parts left off match anything, so estimates err on the high side.
+    pub(crate) fn matches(
+        &self,
+        element_code: &str,
+        height_depth: Option<i32>,
+        ordinal: Option<i32>,
+    ) -> bool {
+        let code = match &self.element_code {
+            Part::Value(c) => c == element_code,
+            Part::Any => true,
+        };
+
+        let depth = match (self.height_depth, height_depth) {
+            (Some(Part::Value(a)), Some(b)) => a == b,
+            (Some(Part::Value(_)), None) => false,
+            _ => true,
+        };
+
+        let ordinal = match (self.ordinal, ordinal) {
+            (Some(Part::Value(a)), Some(b)) => i64::from(a) == i64::from(b),
+            _ => true,
+        };
+
+        code && depth && ordinal
+    }
+
+    // check the element code against the codes in the elements reference list,
+    // suggesting the closest codes (and codes whose name matches) if it's not
+    // there
+    pub(crate) fn check_code(
+        &self,
+        codes: &[String],
+        names: &[String],
+    ) -> Result<(), ElementError> {
+        let Part::Value(code) = &self.element_code else {
+            return Ok(());
+        };
+
+        if codes.iter().any(|c| c == code) {
+            return Ok(());
+        }
+
+        Err(
+            ElementError::new(&self.to_string(), &format!("unknown element code `{code}`"))
+                .with_suggestions(suggestions(code, codes, names)),
+        )
+    }
+}
+
+fn suggestions(code: &str, codes: &[String], names: &[String]) -> Vec<String> {
+    const MAX_DISTANCE: usize = 2;
+    const MAX_SUGGESTIONS: usize = 3;
+
+    let lower = code.to_ascii_lowercase();
+
+    let mut candidates: Vec<(usize, &String)> = codes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| {
+            let distance = levenshtein(code, c);
+            let name_matches = lower.len() >= 3
+                && names
+                    .get(i)
+                    .map(|n| n.to_ascii_lowercase().contains(&lower))
+                    .unwrap_or(false);
+
+            if distance <= MAX_DISTANCE {
+                Some((distance, c))
+            } else if name_matches {
+                Some((MAX_DISTANCE + 1, c))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    candidates.sort();
+    candidates.dedup_by(|a, b| a.1 == b.1);
+
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, c)| c.clone())
+        .collect()
+}
+
+// edit distance between two codes, which are short enough that the simple
+// two-row version is plenty
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
@@ -0,0 +1,575 @@
+use extendr_api::prelude::*;
+
+use crate::batch::Batch;
+use crate::dates;
+
+// PARSE ERRORS ----------------------------------------------------------------
+// rather than panic on a bad response, hand R a condition object describing
+// where parsing failed, then let R raise it with `cli::cli_abort()`
+#[derive(Debug, Clone)]
+pub(crate) struct ParseError {
+    chunk: usize,
+    line: usize,
+    column: usize,
+    path: String,
+    snippet: String,
+    message: String,
+}
+
+impl ParseError {
+    // `chunk` is the one-based position of the json string in the vector
+    // passed from R, so it lines up with R indexing
+    pub(crate) fn new(chunk: usize, json: &str, e: &serde_json::Error) -> Self {
+        let offset = byte_offset(json, e.line(), e.column());
+
+        ParseError {
+            chunk,
+            line: e.line(),
+            column: e.column(),
+            path: json_path(json, offset),
+            snippet: snippet(json, offset),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<ParseError> for Robj {
+    fn from(x: ParseError) -> Self {
+        let mut condition = list!(
+            message = x.message,
+            call = NULL,
+            chunk = x.chunk as i32,
+            line = x.line as i32,
+            column = x.column as i32,
+            path = x.path,
+            snippet = x.snippet
+        );
+
+        condition
+            .set_class(&["awdb_parse_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// UNIT ERRORS -----------------------------------------------------------------
+// raised when a unit code isn't in the conversion registry, or when asked to
+// convert between units that measure different things
+#[derive(Debug, Clone)]
+pub(crate) struct UnitError {
+    unit_code: String,
+    element_code: String,
+    station_triplet: String,
+    reason: String,
+}
+
+impl UnitError {
+    pub(crate) fn unrecognized(code: &str) -> Self {
+        UnitError {
+            unit_code: code.to_string(),
+            element_code: String::new(),
+            station_triplet: String::new(),
+            reason: format!("unrecognized unit code `{code}`"),
+        }
+    }
+
+    pub(crate) fn incompatible(from: &str, to: &str) -> Self {
+        UnitError {
+            unit_code: from.to_string(),
+            element_code: String::new(),
+            station_triplet: String::new(),
+            reason: format!("can't convert `{from}` to `{to}`"),
+        }
+    }
+
+    // an update came back in different units than the data it updates
+    pub(crate) fn mismatch(old: &str, new: &str) -> Self {
+        UnitError {
+            unit_code: new.to_string(),
+            element_code: String::new(),
+            station_triplet: String::new(),
+            reason: format!(
+                "existing values are in `{old}` but updated values are in `{new}`; use the same `units` as the original request"
+            ),
+        }
+    }
+
+    // fill in where the offending unit was found
+    pub(crate) fn at(mut self, element_code: &str, station_triplet: &str) -> Self {
+        self.element_code = element_code.to_string();
+        self.station_triplet = station_triplet.to_string();
+        self
+    }
+}
+
+impl From<UnitError> for Robj {
+    fn from(x: UnitError) -> Self {
+        let message = format!(
+            "{} for element `{}` at station `{}`",
+            x.reason, x.element_code, x.station_triplet
+        );
+
+        let mut condition = list!(
+            message = message,
+            call = NULL,
+            unit_code = x.unit_code,
+            element_code = x.element_code,
+            station_triplet = x.station_triplet
+        );
+
+        condition
+            .set_class(&["awdb_unit_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// TRIPLET ERRORS --------------------------------------------------------------
+// an invalid station triplet, either passed in from R or read from a response
+#[derive(Debug, Clone)]
+pub(crate) struct TripletError {
+    station_triplet: String,
+    message: String,
+}
+
+impl TripletError {
+    pub(crate) fn new(station_triplet: &str, reason: &str) -> Self {
+        TripletError {
+            station_triplet: station_triplet.to_string(),
+            message: format!("invalid station triplet `{station_triplet}`: {reason}"),
+        }
+    }
+}
+
+impl std::fmt::Display for TripletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<TripletError> for Robj {
+    fn from(x: TripletError) -> Self {
+        let mut condition = list!(
+            message = x.message,
+            call = NULL,
+            station_triplet = x.station_triplet
+        );
+
+        condition
+            .set_class(&["awdb_triplet_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// ELEMENT ERRORS --------------------------------------------------------------
+// an element triplet that doesn't follow the grammar or names an unknown code,
+// with any close matches from the elements reference list
+#[derive(Debug, Clone)]
+pub(crate) struct ElementError {
+    element_triplet: String,
+    message: String,
+    suggestions: Vec<String>,
+}
+
+impl ElementError {
+    pub(crate) fn new(element_triplet: &str, reason: &str) -> Self {
+        ElementError {
+            element_triplet: element_triplet.to_string(),
+            message: format!("invalid element `{element_triplet}`: {reason}"),
+            suggestions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_suggestions(mut self, suggestions: Vec<String>) -> Self {
+        self.suggestions = suggestions;
+        self
+    }
+}
+
+impl From<ElementError> for Robj {
+    fn from(x: ElementError) -> Self {
+        let mut condition = list!(
+            message = x.message,
+            call = NULL,
+            element_triplet = x.element_triplet,
+            suggestions = x.suggestions
+        );
+
+        condition
+            .set_class(&["awdb_element_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// PARSE DIAGNOSTICS -----------------------------------------------------------
+// in lenient mode a chunk that fails to parse is skipped rather than raised,
+// and this table of failures is attached to the result as an attribute
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ParseDiagnostics {
+    chunk: Vec<i32>,
+    station_triplets: Vec<String>,
+    message: Vec<String>,
+}
+
+impl ParseDiagnostics {
+    // `station_triplets` are the stations in the failed chunk, comma separated
+    pub(crate) fn push(&mut self, error: ParseError, station_triplets: String) {
+        self.chunk.push(error.chunk as i32);
+        self.station_triplets.push(station_triplets);
+        self.message.push(error.message);
+    }
+}
+
+impl From<ParseDiagnostics> for Robj {
+    fn from(x: ParseDiagnostics) -> Self {
+        let mut df = data_frame!(
+            chunk = x.chunk,
+            station_triplets = x.station_triplets,
+            message = x.message
+        );
+
+        df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+        df
+    }
+}
+
+// REQUEST ERRORS --------------------------------------------------------------
+// a request that never got a response, or got a 4xx or 5xx status back
+#[derive(Debug, Clone)]
+pub(crate) struct RequestError {
+    url: String,
+    status: Option<u32>,
+    message: String,
+    // a cassette couldn't be read or written, or didn't have the request
+    cassette: bool,
+    // the user pressed Ctrl-C (or Esc) while waiting
+    interrupted: bool,
+}
+
+impl RequestError {
+    pub(crate) fn status(url: &str, status: u32, body: &str) -> Self {
+        // AWDB explains most bad requests in the body, so keep the start of it
+        let mut end = body.len().min(200);
+
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let body = body[..end].split_whitespace().collect::<Vec<_>>().join(" ");
+
+        RequestError {
+            url: url.to_string(),
+            status: Some(status),
+            message: if body.is_empty() {
+                format!("HTTP {status}")
+            } else {
+                format!("HTTP {status}: {body}")
+            },
+            cassette: false,
+            interrupted: false,
+        }
+    }
+
+    pub(crate) fn transport(url: &str, message: &str) -> Self {
+        RequestError {
+            url: url.to_string(),
+            status: None,
+            message: message.to_string(),
+            cassette: false,
+            interrupted: false,
+        }
+    }
+
+    // `url` is the request, or the cassette's path if the problem is the file
+    pub(crate) fn cassette(url: &str, message: &str) -> Self {
+        RequestError {
+            url: url.to_string(),
+            status: None,
+            message: message.to_string(),
+            cassette: true,
+            interrupted: false,
+        }
+    }
+
+    // the requests were cancelled, so there's no one url to blame
+    pub(crate) fn interrupted() -> Self {
+        RequestError {
+            url: String::new(),
+            status: None,
+            message: "interrupted by the user".to_string(),
+            cassette: false,
+            interrupted: true,
+        }
+    }
+
+    pub(crate) fn is_cassette(&self) -> bool {
+        self.cassette
+    }
+
+    // rate limited or a server error, either of which may clear up
+    pub(crate) fn is_transient(&self) -> bool {
+        matches!(self.status, Some(429) | Some(500..=599))
+    }
+
+    // a server error, which may be down to one station in the request
+    pub(crate) fn is_server_error(&self) -> bool {
+        matches!(self.status, Some(500..=599))
+    }
+}
+
+impl From<RequestError> for Robj {
+    fn from(x: RequestError) -> Self {
+        let mut condition = list!(
+            message = x.message,
+            call = NULL,
+            url = x.url,
+            status = x.status.map(|s| s as i32)
+        );
+
+        if x.interrupted {
+            condition
+                .set_class(&["awdb_interrupt", "awdb_request_error", "error", "condition"])
+                .unwrap();
+        } else if x.cassette {
+            condition
+                .set_class(&[
+                    "awdb_cassette_error",
+                    "awdb_request_error",
+                    "error",
+                    "condition",
+                ])
+                .unwrap();
+        } else {
+            condition
+                .set_class(&["awdb_request_error", "error", "condition"])
+                .unwrap();
+        }
+
+        condition.into_robj()
+    }
+}
+
+// REQUEST FAILURES ------------------------------------------------------------
+// stations whose request still failed after retrying and splitting their batch
+// are dropped from the result, and listed one per row in this table, attached
+// as the "failed_requests" attribute. long periods are requested in date
+// windows, so each row has the window that failed, and the station's other
+// windows may still have come back.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestFailures {
+    station_triplet: Vec<String>,
+    begin_date: Vec<Option<String>>,
+    end_date: Vec<Option<String>>,
+    status: Vec<Option<i32>>,
+    message: Vec<String>,
+    url: Vec<String>,
+}
+
+impl RequestFailures {
+    // `station_triplets` are the comma separated stations of the failed
+    // request, from `batch`
+    pub(crate) fn push(&mut self, error: RequestError, station_triplets: &str, batch: &Batch) {
+        for triplet in station_triplets.split(',') {
+            self.station_triplet.push(triplet.to_string());
+            self.begin_date.push(batch.begin_date.clone());
+            self.end_date.push(batch.end_date.clone());
+            self.status.push(error.status.map(|s| s as i32));
+            self.message.push(error.message.clone());
+            self.url.push(error.url.clone());
+        }
+    }
+}
+
+impl From<RequestFailures> for Robj {
+    fn from(x: RequestFailures) -> Self {
+        let mut df = data_frame!(
+            station_triplet = x.station_triplet,
+            begin_date = dates::dates_robj(x.begin_date.iter().map(|d| d.as_deref())),
+            end_date = dates::dates_robj(x.end_date.iter().map(|d| d.as_deref())),
+            status = x.status,
+            message = x.message,
+            url = x.url
+        );
+
+        df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+        df
+    }
+}
+
+// WRITE ERRORS ----------------------------------------------------------------
+// an Arrow or Parquet file that couldn't be written
+#[derive(Debug, Clone)]
+pub(crate) struct WriteError {
+    path: String,
+    message: String,
+}
+
+impl WriteError {
+    pub(crate) fn new(path: &str, reason: &str) -> Self {
+        WriteError {
+            path: path.to_string(),
+            message: format!("can't write `{path}`: {reason}"),
+        }
+    }
+}
+
+impl From<WriteError> for Robj {
+    fn from(x: WriteError) -> Self {
+        let mut condition = list!(message = x.message, call = NULL, path = x.path);
+
+        condition
+            .set_class(&["awdb_write_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// AOI ERRORS ------------------------------------------------------------------
+// an area of interest that isn't a polygon or multipolygon, or whose WKB or
+// WKT couldn't be read
+#[derive(Debug, Clone)]
+pub(crate) struct AoiError {
+    message: String,
+}
+
+impl AoiError {
+    pub(crate) fn new(reason: &str) -> Self {
+        AoiError {
+            message: format!("can't read area of interest: {reason}"),
+        }
+    }
+}
+
+impl From<AoiError> for Robj {
+    fn from(x: AoiError) -> Self {
+        let mut condition = list!(message = x.message, call = NULL);
+
+        condition
+            .set_class(&["awdb_aoi_error", "error", "condition"])
+            .unwrap();
+
+        condition.into_robj()
+    }
+}
+
+// serde_json reports a one-based line and the number of bytes consumed on
+// that line, so walk the lines to get back to a byte offset
+fn byte_offset(json: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = json
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|l| l.len())
+        .sum();
+
+    (line_start + column).min(json.len())
+}
+
+// a short window of the payload around the failure, trimmed to valid char
+// boundaries and squashed onto one line
+fn snippet(json: &str, offset: usize) -> String {
+    const HALF_WIDTH: usize = 40;
+
+    let mut start = offset.saturating_sub(HALF_WIDTH);
+    let mut end = (offset + HALF_WIDTH).min(json.len());
+
+    while !json.is_char_boundary(start) {
+        start -= 1;
+    }
+
+    while !json.is_char_boundary(end) {
+        end += 1;
+    }
+
+    json[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// scan the payload up to the failure and keep track of which object keys and
+// array indices are open, e.g. `$[3].data[0].stationElement.ordinal`
+enum Frame {
+    Object(Option<String>),
+    Array(usize),
+}
+
+fn json_path(json: &str, offset: usize) -> String {
+    let mut offset = offset.min(json.len());
+
+    while !json.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut expect_key = false;
+    let mut chars = json[..offset].chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut s = String::new();
+                let mut escaped = false;
+
+                for c in chars.by_ref() {
+                    match (escaped, c) {
+                        (false, '\\') => escaped = true,
+                        (false, '"') => break,
+                        _ => {
+                            escaped = false;
+                            s.push(c);
+                        }
+                    }
+                }
+
+                if expect_key {
+                    if let Some(Frame::Object(key)) = stack.last_mut() {
+                        *key = Some(s);
+                    }
+                    expect_key = false;
+                }
+            }
+            '{' => {
+                stack.push(Frame::Object(None));
+                expect_key = true;
+            }
+            '[' => {
+                stack.push(Frame::Array(0));
+                expect_key = false;
+            }
+            '}' | ']' => {
+                stack.pop();
+                expect_key = false;
+            }
+            ',' => match stack.last_mut() {
+                Some(Frame::Array(i)) => *i += 1,
+                Some(Frame::Object(_)) => expect_key = true,
+                None => (),
+            },
+            _ => (),
+        }
+    }
+
+    let mut path = String::from("$");
+
+    for frame in stack {
+        match frame {
+            Frame::Object(Some(key)) => {
+                path.push('.');
+                path.push_str(&key);
+            }
+            Frame::Object(None) => (),
+            Frame::Array(i) => path.push_str(&format!("[{i}]")),
+        }
+    }
+
+    path
+}
@@ -0,0 +1,372 @@
+use extendr_api::Robj;
+use serde::de::DeserializeOwned;
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache::Cache;
+use crate::cassette::{Cassette, Mode};
+use crate::errors::{ParseError, RequestError};
+use crate::from_json;
+use crate::http::{self, Response};
+
+// FETCH -----------------------------------------------------------------------
+// requests to the AWDB endpoints run on a small pool of threads, and each
+// response is parsed on the thread that received it, so parsing one batch
+// overlaps with waiting on the others
+
+// `base_url` is the root of the REST API, e.g.
+// "https://wcc.sc.egov.usda.gov/awdbRestApi/services/v1", which can be pointed
+// at a local server for testing
+pub(crate) fn url(base_url: &str, endpoint: &str, query: &[(String, String)]) -> String {
+    let mut url = format!("{}/{}", base_url.trim_end_matches('/'), endpoint);
+
+    for (i, (key, value)) in query.iter().enumerate() {
+        url.push(if i == 0 { '?' } else { '&' });
+        url.push_str(&encode(key));
+        url.push('=');
+        url.push_str(&encode(value));
+    }
+
+    url
+}
+
+// percent-encode a query key or value, leaving the unreserved characters and
+// the separators AWDB uses in lists and triplets (`,`, `:` and `*`) as they are
+fn encode(x: &str) -> String {
+    let mut encoded = String::with_capacity(x.len());
+
+    for b in x.bytes() {
+        match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b','
+            | b':'
+            | b'*' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+
+    encoded
+}
+
+// a GET request to one of the endpoints
+pub(crate) struct Request<'a> {
+    pub(crate) base_url: &'a str,
+    pub(crate) endpoint: &'a str,
+    pub(crate) query: Vec<(String, String)>,
+}
+
+impl Request<'_> {
+    pub(crate) fn url(&self) -> String {
+        url(self.base_url, self.endpoint, &self.query)
+    }
+
+    // the url with the query sorted by key, so the same request always gives
+    // the same key however its parameters were ordered, e.g.
+    // "https://wcc.sc.egov.usda.gov/awdbRestApi/services/v1/reference-data?referenceLists=elements"
+    pub(crate) fn key(&self) -> String {
+        let mut query = self.query.clone();
+        query.sort();
+
+        url(self.base_url, self.endpoint, &query)
+    }
+}
+
+// CLIENT ----------------------------------------------------------------------
+// where responses come from: the network, by way of the cache if there is
+// one, or a cassette. a cassette bypasses the cache either way, so that
+// recording sees every response and replaying never sees anything else.
+// cancelling a client stops its requests, including any under way.
+#[derive(Default)]
+pub(crate) struct Client {
+    cache: Option<Cache>,
+    cassette: Option<Cassette>,
+    cancelled: AtomicBool,
+}
+
+impl Client {
+    // from R: the cache directory and cassette, each of which may be NULL
+    pub(crate) fn from_robj(cache_dir: &Robj, cassette: &Robj) -> Result<Self, RequestError> {
+        Ok(Client {
+            cache: Cache::from_robj(cache_dir),
+            cassette: Cassette::from_robj(cassette)?,
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    fn cache(&self) -> Option<&Cache> {
+        match self.cassette {
+            Some(_) => None,
+            None => self.cache.as_ref(),
+        }
+    }
+
+    fn cassette(&self, mode: Mode) -> Option<&Cassette> {
+        self.cassette.as_ref().filter(|c| c.mode() == mode)
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // save anything recorded
+    pub(crate) fn finish(&self) -> Result<(), RequestError> {
+        match &self.cassette {
+            Some(cassette) => cassette.save(),
+            None => Ok(()),
+        }
+    }
+}
+
+// RETRIES ---------------------------------------------------------------------
+// 429 and 5xx responses are usually the server being busy, so those requests
+// are tried again after waiting 1, 2, then 4 seconds, plus up to half as much
+// again at random so that threads rate limited together don't retry together
+const MAX_RETRIES: u32 = 3;
+const BASE_DELAY_MS: u64 = 1000;
+
+fn backoff(retry: u32) -> Duration {
+    let delay = BASE_DELAY_MS << retry;
+
+    // the clock's nanoseconds are random enough to spread retries out
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u64);
+
+    Duration::from_millis(delay + nanos % (delay / 2 + 1))
+}
+
+// wait out a backoff, a little at a time so a cancelled client stops waiting
+fn sleep(duration: Duration, client: &Client) {
+    let step = Duration::from_millis(100);
+    let mut slept = Duration::ZERO;
+
+    while slept < duration && !client.is_cancelled() {
+        std::thread::sleep(step.min(duration - slept));
+        slept += step;
+    }
+}
+
+// treat any 4xx or 5xx status as a failure
+fn check_status(url: &str, response: Response) -> Result<String, RequestError> {
+    if response.status < 400 {
+        Ok(response.body)
+    } else {
+        Err(RequestError::status(url, response.status, &response.body))
+    }
+}
+
+// one GET, retrying transient failures with exponential backoff. a replayed
+// response is final, since the cassette only holds the last try.
+fn fetch(request: &Request, client: &Client) -> Result<String, RequestError> {
+    let url = request.url();
+
+    if let Some(cassette) = client.cassette(Mode::Replay) {
+        return cassette.replay(request).and_then(|r| check_status(&url, r));
+    }
+
+    let mut retry = 0;
+
+    loop {
+        if client.is_cancelled() {
+            return Err(RequestError::interrupted());
+        }
+
+        let response = http::get(&url, &client.cancelled)
+            .map_err(|message| RequestError::transport(&url, &message));
+
+        if let (Ok(response), Some(cassette)) = (&response, client.cassette(Mode::Record)) {
+            cassette.record(request, response);
+        }
+
+        match response.and_then(|r| check_status(&url, r)) {
+            Err(e) if e.is_transient() && retry < MAX_RETRIES => {
+                sleep(backoff(retry), client);
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub(crate) type Fetched<T> = Result<Result<T, ParseError>, RequestError>;
+
+// GET and parse a request, using the cached body if there's a fresh one. only
+// bodies that parse are cached.
+pub(crate) fn fetch_parsed<T, P>(request: &Request, client: &Client, parse: P) -> Fetched<T>
+where
+    P: FnOnce(&str) -> Result<T, ParseError>,
+{
+    let cache = client.cache();
+
+    if let Some(body) = cache.and_then(|c| c.get(request)) {
+        return Ok(parse(&body));
+    }
+
+    let body = fetch(request, client)?;
+    let parsed = parse(&body);
+
+    if let (Ok(_), Some(cache)) = (&parsed, cache) {
+        cache.put(request, &body);
+    }
+
+    Ok(parsed)
+}
+
+// a batch's results, one for each part it was split into, with the comma
+// separated stations in that part
+pub(crate) type Parts<T> = Vec<(String, Fetched<T>)>;
+
+// BISECTION -------------------------------------------------------------------
+// a batch of stations that still fails after retrying is split in half, and
+// each half is requested on its own, down to single stations. that way one
+// station the server chokes on only costs its own data, not its whole batch.
+// only failures one station could cause are split: a server error or a
+// response that doesn't parse. a bad request (400), a missing endpoint (404),
+// a timeout or a dropped connection would fail every half the same way, so
+// those go back for the whole batch at once.
+fn fetch_batch<'a, T, F>(stations: &str, request: &F, client: &Client, i: usize) -> Parts<T>
+where
+    T: DeserializeOwned,
+    F: Fn(usize, &str) -> Request<'a>,
+{
+    let parse = |body: &str| from_json::<T>(body, i + 1);
+
+    let result = fetch_parsed(&request(i, stations), client, parse);
+
+    let split = match &result {
+        Ok(Ok(_)) => false,
+        Ok(Err(_)) => true,
+        Err(e) => e.is_server_error(),
+    };
+
+    let triplets: Vec<&str> = stations.split(',').collect();
+
+    if !split || triplets.len() < 2 {
+        return vec![(stations.to_string(), result)];
+    }
+
+    let (left, right) = triplets.split_at(triplets.len() / 2);
+
+    let mut results = fetch_batch(&left.join(","), request, client, i);
+    results.extend(fetch_batch(&right.join(","), request, client, i));
+
+    results
+}
+
+// INTERRUPTS ------------------------------------------------------------------
+// R only notices Ctrl-C (or Esc) when it's asked, and asking with
+// R_CheckUserInterrupt() jumps straight back to the prompt, which would skip
+// past Rust's destructors and leave the worker threads running. running it
+// under R_ToplevelExec() catches the jump, so all we see is whether it
+// happened. both are part of R's API, and like the rest of it may only be
+// called from R's main thread.
+extern "C" {
+    fn R_ToplevelExec(fun: extern "C" fn(*mut c_void), data: *mut c_void) -> c_int;
+    fn R_CheckUserInterrupt();
+}
+
+extern "C" fn check_user_interrupt(_data: *mut c_void) {
+    // SAFETY: only called by R_ToplevelExec(), on R's main thread
+    unsafe { R_CheckUserInterrupt() }
+}
+
+// whether the user has interrupted R since it last checked
+fn interrupted() -> bool {
+    // SAFETY: `check_user_interrupt` takes no data, and this is only called
+    // from the thread R called into Rust on
+    unsafe { R_ToplevelExec(check_user_interrupt, std::ptr::null_mut()) == 0 }
+}
+
+// how often the main thread stops waiting on the workers to check for an
+// interrupt
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+// fetch and parse every batch of comma separated station triplets on up to
+// `workers` threads, or one per available core if zero. `request` builds the
+// request for some or all of the stations in the batch at a given position,
+// and `client` says where responses come from. results keep the order of
+// `batches`, and parse errors report the one-based position of the batch as
+// their chunk. this must be called from R's main thread, which waits on the
+// workers and checks for an interrupt while it does. an interrupt cancels the
+// client, waits for the workers to wind down, and returns an error.
+pub(crate) fn fetch_batches<'a, T, F>(
+    batches: &[String],
+    request: F,
+    client: &Client,
+    workers: usize,
+) -> Result<Vec<Parts<T>>, RequestError>
+where
+    T: DeserializeOwned + Send,
+    F: Fn(usize, &str) -> Request<'a> + Sync,
+{
+    let workers = if workers == 0 {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        workers
+    }
+    .min(batches.len())
+    .max(1);
+
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<Parts<T>>> = (0..batches.len()).map(|_| None).collect();
+
+    std::thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let next = &next;
+            let request = &request;
+
+            s.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+
+                if i >= batches.len() || client.is_cancelled() {
+                    break;
+                }
+
+                let result = fetch_batch::<T, F>(&batches[i], request, client, i);
+
+                if tx.send((i, result)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        drop(tx);
+
+        loop {
+            match rx.recv_timeout(INTERRUPT_POLL) {
+                Ok((i, result)) => results[i] = Some(result),
+                Err(RecvTimeoutError::Timeout) => {
+                    if interrupted() {
+                        client.cancel();
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+
+    if client.is_cancelled() {
+        return Err(RequestError::interrupted());
+    }
+
+    // every index is claimed by exactly one worker, and a worker only stops
+    // early if the client was cancelled, which returned above, or if the
+    // receiver is gone, which can't happen before this point
+    Ok(results.into_iter().map(|r| r.unwrap()).collect())
+}
@@ -0,0 +1,193 @@
+use extendr_api::prelude::*;
+
+use crate::huc;
+use crate::StationMetadataSet;
+
+// GEOMETRY --------------------------------------------------------------------
+// station locations built straight into an `sf` object, so R never has to
+// call `sf::st_as_sf()` on the station list. this mirrors what sf itself
+// builds: a `sfc_POINT` list column of `sfg` points with its bbox, crs,
+// precision and n_empty attributes (and z_range, for points with elevation),
+// and a data frame with the sf_column and agr attributes pointing to it.
+// coordinates are longitude and latitude in WGS 84, and elevation is in feet
+// as AWDB reports it.
+
+// whether points carry elevation as a Z coordinate, and the crs they're in.
+// the crs is `sf::st_crs(4326)` built by sf in R, so that crs comparisons and
+// transforms in R work as they would on one sf built itself.
+pub(crate) struct Geometry {
+    z: bool,
+    crs: Robj,
+}
+
+impl Geometry {
+    // from R: NULL for plain latitude and longitude columns, or
+    // list(dim = , crs = ) with dim "xy" or "xyz"
+    pub(crate) fn from_robj(x: &Robj) -> Option<Self> {
+        let fields = x.as_list()?;
+        let field = |name: &str| fields.iter().find(|(n, _)| *n == name).map(|(_, x)| x);
+
+        let z = match field("dim")?.as_str()? {
+            "xy" => false,
+            "xyz" => true,
+            _ => return None,
+        };
+
+        Some(Geometry {
+            z,
+            crs: field("crs")?,
+        })
+    }
+}
+
+// the smallest and largest value, ignoring NAs, or NA if there are none
+fn range<I: Iterator<Item = f64>>(x: I) -> (f64, f64) {
+    x.filter(|v| !v.is_nan())
+        .fold(None, |acc: Option<(f64, f64)>, v| match acc {
+            Some((lo, hi)) => Some((lo.min(v), hi.max(v))),
+            None => Some((v, v)),
+        })
+        .unwrap_or((f64::NAN, f64::NAN))
+}
+
+// a named, classed numeric vector, like the bbox of an sfc, with NaN as NA
+fn classed_doubles(names: &[&str], values: &[f64], class: &str) -> Robj {
+    let mut robj = values
+        .iter()
+        .map(|v| {
+            if v.is_nan() {
+                Rfloat::na()
+            } else {
+                Rfloat::from(*v)
+            }
+        })
+        .collect::<Doubles>()
+        .into_robj();
+
+    robj.set_names(names).unwrap();
+    robj.set_class(&[class]).unwrap();
+
+    robj
+}
+
+// a `sfc_POINT` column in `crs` from longitude (x), latitude (y) and,
+// optionally, elevation (z), where a missing elevation becomes an NA z
+// coordinate
+pub(crate) fn sfc_point(x: &[f64], y: &[f64], z: Option<&[Option<f64>]>, crs: Robj) -> Robj {
+    let dim = if z.is_some() { "XYZ" } else { "XY" };
+
+    let points: Vec<Robj> = (0..x.len())
+        .map(|i| {
+            let mut coords = vec![Rfloat::from(x[i]), Rfloat::from(y[i])];
+
+            if let Some(z) = z {
+                coords.push(z[i].map_or(Rfloat::na(), Rfloat::from));
+            }
+
+            let mut point = coords.into_iter().collect::<Doubles>().into_robj();
+            point.set_class(&[dim, "POINT", "sfg"]).unwrap();
+            point
+        })
+        .collect();
+
+    let (xmin, xmax) = range(x.iter().copied());
+    let (ymin, ymax) = range(y.iter().copied());
+
+    let mut sfc = List::from_values(points).into_robj();
+
+    sfc.set_class(&["sfc_POINT", "sfc"]).unwrap();
+    sfc.set_attrib("precision", 0.0).unwrap();
+    sfc.set_attrib(
+        "bbox",
+        classed_doubles(
+            &["xmin", "ymin", "xmax", "ymax"],
+            &[xmin, ymin, xmax, ymax],
+            "bbox",
+        ),
+    )
+    .unwrap();
+    sfc.set_attrib("crs", crs).unwrap();
+    sfc.set_attrib("n_empty", 0).unwrap();
+
+    if let Some(z) = z {
+        let (zmin, zmax) = range(z.iter().map(|v| v.unwrap_or(f64::NAN)));
+
+        sfc.set_attrib(
+            "z_range",
+            classed_doubles(&["zmin", "zmax"], &[zmin, zmax], "z_range"),
+        )
+        .unwrap();
+    }
+
+    sfc
+}
+
+// swap the `drop` columns of a data frame for `extra` columns and then a
+// "geometry" column at the end, and make it an sf table
+fn as_sf(df: Robj, geometry: Robj, drop: &[&str], mut extra: Vec<(&str, Robj)>) -> Robj {
+    if !df.is_list() {
+        return df;
+    }
+
+    extra.push(("geometry", geometry));
+
+    let mut out = crate::splice_columns(df, drop, None, extra);
+
+    // every column but the geometry
+    let mut names: Vec<&str> = out.names().map(|n| n.collect()).unwrap_or_default();
+    names.pop();
+
+    // attribute-geometry relationships, all NA until set in R
+    let mut agr = vec![Rint::na(); names.len()]
+        .into_iter()
+        .collect::<Integers>()
+        .into_robj();
+
+    agr.set_attrib("levels", ["constant", "aggregate", "identity"])
+        .unwrap();
+    agr.set_class(&["factor"]).unwrap();
+    agr.set_names(&names).unwrap();
+
+    out.set_attrib("sf_column", "geometry").unwrap();
+    out.set_attrib("agr", agr).unwrap();
+    out.set_class(&["sf", "tbl_df", "tbl", "data.frame"])
+        .unwrap();
+
+    out
+}
+
+impl StationMetadataSet {
+    // station metadata as an sf table, with the point geometry in place of the
+    // latitude and longitude columns
+    pub(crate) fn into_sf(self, geometry: Geometry, huc_columns: bool) -> Robj {
+        self.into_sf_with(geometry, huc_columns, Vec::new())
+    }
+
+    // the same, with `extra` columns added before the geometry
+    pub(crate) fn into_sf_with(
+        self,
+        geometry: Geometry,
+        huc_columns: bool,
+        extra: Vec<(&str, Robj)>,
+    ) -> Robj {
+        let longitude: Vec<f64> = self.0.iter().map(|s| s.longitude).collect();
+        let latitude: Vec<f64> = self.0.iter().map(|s| s.latitude).collect();
+        let elevation: Vec<Option<f64>> = self.0.iter().map(|s| s.elevation).collect();
+
+        let z = if geometry.z {
+            Some(elevation.as_slice())
+        } else {
+            None
+        };
+
+        let sfc = sfc_point(&longitude, &latitude, z, geometry.crs);
+
+        let mut df: Robj = self.into();
+
+        if huc_columns {
+            df = huc::with_huc_columns(df, None);
+        }
+
+        as_sf(df, sfc, &["latitude", "longitude"], extra)
+    }
+}
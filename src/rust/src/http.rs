@@ -0,0 +1,181 @@
+// HTTP ------------------------------------------------------------------------
+// a blocking GET over the system libcurl, the same library the curl and httr2
+// R packages use. binding the handful of functions needed here by hand means
+// no TLS stack has to be vendored with the crate. libcurl is linked by the
+// package Makevars (see `tools/config.R`).
+//
+// every request gets its own easy handle, so requests can run on any number
+// of threads at once. a transfer checks its `cancelled` flag as it goes, and
+// gives up as soon as the flag is set, e.g. when the user interrupts R.
+pub(crate) struct Response {
+    pub(crate) status: u32,
+    pub(crate) body: String,
+}
+
+pub(crate) use curl::get;
+
+use std::sync::atomic::AtomicBool;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod curl {
+    use super::{AtomicBool, Response};
+    use std::ffi::{c_char, c_int, c_long, c_void, CStr, CString};
+    use std::sync::atomic::Ordering;
+    use std::sync::Once;
+
+    type Curl = c_void;
+
+    const CURL_GLOBAL_DEFAULT: c_long = 3;
+    const CURLE_OK: c_int = 0;
+
+    const CURLOPT_WRITEDATA: c_int = 10001;
+    const CURLOPT_URL: c_int = 10002;
+    const CURLOPT_USERAGENT: c_int = 10018;
+    const CURLOPT_ACCEPT_ENCODING: c_int = 10102;
+    const CURLOPT_WRITEFUNCTION: c_int = 20011;
+    const CURLOPT_TIMEOUT: c_int = 13;
+    const CURLOPT_FOLLOWLOCATION: c_int = 52;
+    const CURLOPT_CONNECTTIMEOUT: c_int = 78;
+    const CURLOPT_NOSIGNAL: c_int = 99;
+    const CURLOPT_NOPROGRESS: c_int = 43;
+    const CURLOPT_XFERINFODATA: c_int = 10057;
+    const CURLOPT_XFERINFOFUNCTION: c_int = 20219;
+    const CURLINFO_RESPONSE_CODE: c_int = 0x200002;
+
+    extern "C" {
+        fn curl_global_init(flags: c_long) -> c_int;
+        fn curl_easy_init() -> *mut Curl;
+        fn curl_easy_setopt(handle: *mut Curl, option: c_int, ...) -> c_int;
+        fn curl_easy_perform(handle: *mut Curl) -> c_int;
+        fn curl_easy_getinfo(handle: *mut Curl, info: c_int, ...) -> c_int;
+        fn curl_easy_cleanup(handle: *mut Curl);
+        fn curl_easy_strerror(code: c_int) -> *const c_char;
+    }
+
+    // libcurl wants this called once, before any other thread touches it
+    static INIT: Once = Once::new();
+
+    const USER_AGENT: &str = concat!(
+        "awdb (https://github.com/kbvernon/awdb) rust/",
+        env!("CARGO_PKG_VERSION")
+    );
+    const CONNECT_TIMEOUT_SECS: c_long = 30;
+    const TIMEOUT_SECS: c_long = 300;
+
+    type WriteCallback = extern "C" fn(*const c_char, usize, usize, *mut c_void) -> usize;
+    type ProgressCallback = extern "C" fn(*mut c_void, i64, i64, i64, i64) -> c_int;
+
+    // libcurl hands the body over in pieces, which are appended to the
+    // Vec<u8> passed as CURLOPT_WRITEDATA
+    extern "C" fn write_body(
+        ptr: *const c_char,
+        size: usize,
+        nmemb: usize,
+        data: *mut c_void,
+    ) -> usize {
+        let n = size * nmemb;
+
+        // SAFETY: libcurl guarantees `ptr` points to `n` bytes, and `data` is
+        // the Vec<u8> set in `get()`, which outlives the transfer
+        unsafe {
+            let body = &mut *(data as *mut Vec<u8>);
+            body.extend_from_slice(std::slice::from_raw_parts(ptr as *const u8, n));
+        }
+
+        n
+    }
+
+    // libcurl calls this at least once a second while a transfer is going,
+    // with the AtomicBool passed as CURLOPT_XFERINFODATA. anything but zero
+    // aborts the transfer.
+    extern "C" fn check_cancelled(
+        data: *mut c_void,
+        _dltotal: i64,
+        _dlnow: i64,
+        _ultotal: i64,
+        _ulnow: i64,
+    ) -> c_int {
+        // SAFETY: `data` is the AtomicBool passed to `get()`, which outlives
+        // the transfer
+        let cancelled = unsafe { &*(data as *const AtomicBool) };
+
+        cancelled.load(Ordering::Relaxed) as c_int
+    }
+
+    pub(crate) fn get(url: &str, cancelled: &AtomicBool) -> Result<Response, String> {
+        INIT.call_once(|| unsafe {
+            curl_global_init(CURL_GLOBAL_DEFAULT);
+        });
+
+        let url = CString::new(url).map_err(|e| e.to_string())?;
+        let user_agent = CString::new(USER_AGENT).unwrap();
+        let encodings = CString::new("").unwrap();
+        let write: WriteCallback = write_body;
+        let progress: ProgressCallback = check_cancelled;
+        let mut body: Vec<u8> = Vec::new();
+        let mut status: c_long = 0;
+
+        // SAFETY: the handle is created and cleaned up here and never shared,
+        // and every pointer passed to it lives until after curl_easy_cleanup()
+        let code = unsafe {
+            let handle = curl_easy_init();
+
+            if handle.is_null() {
+                return Err("failed to create a libcurl handle".to_string());
+            }
+
+            curl_easy_setopt(handle, CURLOPT_URL, url.as_ptr());
+            curl_easy_setopt(handle, CURLOPT_USERAGENT, user_agent.as_ptr());
+            // an empty string asks for every encoding libcurl can decode
+            curl_easy_setopt(handle, CURLOPT_ACCEPT_ENCODING, encodings.as_ptr());
+            curl_easy_setopt(handle, CURLOPT_FOLLOWLOCATION, 1 as c_long);
+            curl_easy_setopt(handle, CURLOPT_NOSIGNAL, 1 as c_long);
+            curl_easy_setopt(handle, CURLOPT_CONNECTTIMEOUT, CONNECT_TIMEOUT_SECS);
+            curl_easy_setopt(handle, CURLOPT_TIMEOUT, TIMEOUT_SECS);
+            curl_easy_setopt(handle, CURLOPT_WRITEFUNCTION, write);
+            curl_easy_setopt(
+                handle,
+                CURLOPT_WRITEDATA,
+                &mut body as *mut Vec<u8> as *mut c_void,
+            );
+            curl_easy_setopt(handle, CURLOPT_NOPROGRESS, 0 as c_long);
+            curl_easy_setopt(handle, CURLOPT_XFERINFOFUNCTION, progress);
+            curl_easy_setopt(
+                handle,
+                CURLOPT_XFERINFODATA,
+                cancelled as *const AtomicBool as *mut c_void,
+            );
+
+            let code = curl_easy_perform(handle);
+
+            if code == CURLE_OK {
+                curl_easy_getinfo(handle, CURLINFO_RESPONSE_CODE, &mut status as *mut c_long);
+            }
+
+            curl_easy_cleanup(handle);
+
+            code
+        };
+
+        if code != CURLE_OK {
+            // SAFETY: curl_easy_strerror() returns a static, nul-terminated string
+            let message = unsafe { CStr::from_ptr(curl_easy_strerror(code)) };
+            return Err(message.to_string_lossy().into_owned());
+        }
+
+        Ok(Response {
+            status: status as u32,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
+
+// webR runs in the browser, where there is no libcurl to link against
+#[cfg(target_arch = "wasm32")]
+mod curl {
+    use super::{AtomicBool, Response};
+
+    pub(crate) fn get(_url: &str, _cancelled: &AtomicBool) -> Result<Response, String> {
+        Err("HTTP requests are not supported on this platform".to_string())
+    }
+}
@@ -0,0 +1,158 @@
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+
+use crate::{StationMetadata, StationMetadataSet};
+
+// HYDROLOGIC UNITS ------------------------------------------------------------
+// a HUC is nested two digits at a time, from the region (HUC2) down to the
+// subwatershed (HUC12), e.g. "160102030101" lies in "1601", which lies in
+// "16". stations are filtered by any of these prefixes, and each level can be
+// added as its own `huc2` through `huc12` column to aggregate by basin.
+const LEVELS: [(usize, &str); 6] = [
+    (2, "huc2"),
+    (4, "huc4"),
+    (6, "huc6"),
+    (8, "huc8"),
+    (10, "huc10"),
+    (12, "huc12"),
+];
+
+fn is_huc(x: &str) -> bool {
+    !x.is_empty() && x.len() <= 12 && x.len() % 2 == 0 && x.bytes().all(|b| b.is_ascii_digit())
+}
+
+// the code at one level of a HUC, or None if it isn't that fine
+fn level(huc: &str, digits: usize) -> Option<&str> {
+    let huc = huc.trim();
+
+    if huc.len() >= digits && huc.bytes().all(|b| b.is_ascii_digit()) {
+        Some(&huc[..digits])
+    } else {
+        None
+    }
+}
+
+// stations are kept if their HUC starts with any of the codes
+#[derive(Debug)]
+pub(crate) struct HucFilter(Vec<String>);
+
+impl HucFilter {
+    // from R: NULL for no filter, or a character vector of HUC2 to HUC12 codes.
+    // codes that aren't an even number of digits are ignored, since R checks
+    // them first.
+    pub(crate) fn from_robj(x: &Robj) -> Option<Self> {
+        let codes: Vec<String> = x
+            .as_str_iter()?
+            .filter(|s| !s.is_na())
+            .flat_map(|s| s.split(','))
+            .map(str::trim)
+            .filter(|s| is_huc(s))
+            .map(str::to_string)
+            .collect();
+
+        if codes.is_empty() {
+            None
+        } else {
+            Some(HucFilter(codes))
+        }
+    }
+
+    pub(crate) fn matches(&self, huc: Option<&str>) -> bool {
+        let Some(huc) = huc.map(str::trim) else {
+            return false;
+        };
+
+        self.0.iter().any(|code| huc.starts_with(code.as_str()))
+    }
+
+    pub(crate) fn keep(&self, station: &StationMetadata) -> bool {
+        self.matches(station.huc.as_deref())
+    }
+}
+
+impl StationMetadataSet {
+    pub(crate) fn retain_hucs(&mut self, filter: &HucFilter) {
+        self.0.retain(|s| filter.keep(s));
+    }
+}
+
+// each level of each HUC, as (name, codes) columns from `huc2` to `huc12`
+pub(crate) fn huc_levels<'a, I>(hucs: I) -> Vec<(&'static str, Vec<Option<String>>)>
+where
+    I: Iterator<Item = Option<&'a str>> + Clone,
+{
+    LEVELS
+        .iter()
+        .map(|&(digits, name)| {
+            let codes = hucs
+                .clone()
+                .map(|h| h.and_then(|h| level(h, digits)).map(str::to_string))
+                .collect();
+
+            (name, codes)
+        })
+        .collect()
+}
+
+// the HUC of each station, named by station triplet, for tables that only have
+// a `station_triplet` column
+#[derive(Debug, Default)]
+pub(crate) struct StationHucs(HashMap<String, String>);
+
+impl StationHucs {
+    // from R: NULL for no HUC columns, or a character vector of HUCs named by
+    // station triplet
+    pub(crate) fn from_robj(x: &Robj) -> Option<Self> {
+        if x.is_null() {
+            return None;
+        }
+
+        Some(StationHucs(crate::named_strings(x).into_iter().collect()))
+    }
+
+    pub(crate) fn get(&self, station_triplet: &str) -> Option<&str> {
+        self.0.get(station_triplet).map(|h| h.as_str())
+    }
+}
+
+// add `huc2` through `huc12` columns to a data frame, taken from its `huc`
+// column or, without one, from `hucs` by station triplet. they go right after
+// the `huc` column, or after the station triplet and any triplet columns.
+pub(crate) fn with_huc_columns(df: Robj, hucs: Option<&StationHucs>) -> Robj {
+    let Ok(lst) = List::try_from(&df) else {
+        return df;
+    };
+
+    let names: Vec<&str> = lst.names().map(|n| n.collect()).unwrap_or_default();
+    let position = |name: &str| names.iter().position(|n| *n == name);
+
+    let strings = |i: Option<usize>| -> Vec<Option<String>> {
+        i.and_then(|i| lst.elt(i).ok())
+            .map(|x| crate::strings(&x))
+            .unwrap_or_default()
+    };
+
+    let n_row = lst.elt(0).map(|x| x.len()).unwrap_or_default();
+
+    let huc: Vec<Option<String>> = match (position("huc"), hucs) {
+        (Some(i), _) => strings(Some(i)),
+        (None, Some(hucs)) => strings(position("station_triplet"))
+            .iter()
+            .map(|t| t.as_deref().and_then(|t| hucs.get(t)).map(str::to_string))
+            .collect(),
+        (None, None) => vec![None; n_row],
+    };
+
+    let Some(after) = ["huc", "network_code", "station_triplet"]
+        .iter()
+        .find_map(|name| position(name))
+    else {
+        return df;
+    };
+
+    let levels = huc_levels(huc.iter().map(|h| h.as_deref()))
+        .into_iter()
+        .map(|(name, codes)| (name, codes.into_robj()));
+
+    crate::splice_columns(df, &[], Some(after), levels)
+}
@@ -0,0 +1,471 @@
+use crate::arrow::{dictionary, Column, Field, RecordBatch};
+
+// ARROW IPC -------------------------------------------------------------------
+// the Arrow IPC file format (a.k.a. Feather v2), see
+// https://arrow.apache.org/docs/format/Columnar.html#ipc-file-format
+//
+// a file is the magic "ARROW1", then a schema message, a dictionary batch for
+// each dictionary encoded column, the record batch, and a footer indexing
+// them. message metadata are flatbuffers, written by the small builder below,
+// and message bodies are the column buffers, each padded to 8 bytes.
+
+const MAGIC: &[u8] = b"ARROW1";
+const CONTINUATION: [u8; 4] = [0xFF; 4];
+
+// MetadataVersion::V5
+const VERSION: i16 = 4;
+
+// MessageHeader union
+const SCHEMA: u8 = 1;
+const DICTIONARY_BATCH: u8 = 2;
+const RECORD_BATCH: u8 = 3;
+
+// Type union
+const TYPE_INT: u8 = 2;
+const TYPE_FLOATING_POINT: u8 = 3;
+const TYPE_UTF8: u8 = 5;
+const TYPE_BOOL: u8 = 6;
+const TYPE_DATE: u8 = 8;
+const TYPE_TIMESTAMP: u8 = 10;
+
+pub(crate) fn write(batch: &RecordBatch) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    pad(&mut out, 8);
+
+    let schema = schema(batch);
+    encapsulate(&mut out, message(SCHEMA, schema.clone(), 0), &[]);
+
+    let mut dictionaries = Vec::new();
+
+    for (id, f) in batch.fields.iter().enumerate() {
+        if let Column::Dictionary(x) = &f.column {
+            let (_, values) = dictionary(x);
+            let values = Column::Utf8(values.into_iter().map(|v| Some(v.to_string())).collect());
+
+            let mut body = Body::default();
+            body.push_column(&values);
+
+            let header = Table::default()
+                .with(0, Value::I64(id as i64))
+                .with(1, Value::Table(body.record_batch(values.len())))
+                .with(2, Value::Bool(false));
+
+            let meta = message(DICTIONARY_BATCH, header, body.bytes.len() as i64);
+            dictionaries.push(encapsulate(&mut out, meta, &body.bytes));
+        }
+    }
+
+    let mut body = Body::default();
+
+    for f in batch.fields.iter() {
+        body.push_column(&f.column);
+    }
+
+    let meta = message(
+        RECORD_BATCH,
+        body.record_batch(batch.num_rows()),
+        body.bytes.len() as i64,
+    );
+    let record_batches = vec![encapsulate(&mut out, meta, &body.bytes)];
+
+    // end of stream
+    out.extend_from_slice(&CONTINUATION);
+    out.extend_from_slice(&0i32.to_le_bytes());
+
+    let footer = finish(
+        Table::default()
+            .with(0, Value::I16(VERSION))
+            .with(1, Value::Table(schema))
+            .with(2, Value::Structs(dictionaries))
+            .with(3, Value::Structs(record_batches)),
+    );
+
+    out.extend_from_slice(&footer);
+    out.extend_from_slice(&(footer.len() as i32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+
+    out
+}
+
+// the schema as an encapsulated message, which Parquet files carry (base64
+// encoded) so Arrow readers get back the same types
+pub(crate) fn schema_message(batch: &RecordBatch) -> Vec<u8> {
+    let mut out = Vec::new();
+    encapsulate(&mut out, message(SCHEMA, schema(batch), 0), &[]);
+
+    out
+}
+
+// SCHEMA ----------------------------------------------------------------------
+fn schema(batch: &RecordBatch) -> Table {
+    let fields = batch
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(id, f)| field(f, id as i64))
+        .collect();
+
+    Table::default()
+        .with(0, Value::I16(0))
+        .with(1, Value::Tables(fields))
+}
+
+// dictionary encoded columns take the column's position as their id
+fn field(f: &Field, id: i64) -> Table {
+    let (type_type, type_table) = match &f.column {
+        Column::Utf8(_) | Column::Dictionary(_) => (TYPE_UTF8, Table::default()),
+        Column::Int32(_) => (TYPE_INT, int32()),
+        Column::Float64(_) => (
+            TYPE_FLOATING_POINT,
+            // Precision::DOUBLE
+            Table::default().with(0, Value::I16(2)),
+        ),
+        Column::Boolean(_) => (TYPE_BOOL, Table::default()),
+        // DateUnit::DAY
+        Column::Date(_) => (TYPE_DATE, Table::default().with(0, Value::I16(0))),
+        // TimeUnit::SECOND
+        Column::Timestamp(_) => (
+            TYPE_TIMESTAMP,
+            Table::default()
+                .with(0, Value::I16(0))
+                .with(1, Value::String("UTC".to_string())),
+        ),
+    };
+
+    let mut table = Table::default()
+        .with(0, Value::String(f.name.clone()))
+        .with(1, Value::Bool(f.nullable))
+        .with(2, Value::U8(type_type))
+        .with(3, Value::Table(type_table))
+        .with(5, Value::Tables(Vec::new()));
+
+    if let Column::Dictionary(_) = f.column {
+        table = table.with(
+            4,
+            Value::Table(
+                Table::default()
+                    .with(0, Value::I64(id))
+                    .with(1, Value::Table(int32()))
+                    .with(2, Value::Bool(false)),
+            ),
+        );
+    }
+
+    table
+}
+
+fn int32() -> Table {
+    Table::default()
+        .with(0, Value::I32(32))
+        .with(1, Value::Bool(true))
+}
+
+// BODIES ----------------------------------------------------------------------
+// the buffers of each column back to back, and where each one is
+#[derive(Default)]
+struct Body {
+    bytes: Vec<u8>,
+    // (length, null count) of each column
+    nodes: Vec<Vec<u8>>,
+    // (offset, length) of each buffer
+    buffers: Vec<Vec<u8>>,
+}
+
+impl Body {
+    fn push_buffer(&mut self, data: &[u8]) {
+        let offset = self.bytes.len() as i64;
+
+        self.bytes.extend_from_slice(data);
+        pad(&mut self.bytes, 8);
+        self.buffers.push(pair(offset, data.len() as i64));
+    }
+
+    fn push_column(&mut self, column: &Column) {
+        let n = column.len();
+        let null_count = column.null_count();
+
+        self.nodes.push(pair(n as i64, null_count as i64));
+
+        // no validity bitmap is needed if nothing is missing
+        if null_count == 0 {
+            self.push_buffer(&[]);
+        } else {
+            self.push_buffer(&bitmap((0..n).map(|i| column.is_valid(i))));
+        }
+
+        match column {
+            Column::Utf8(x) => {
+                let mut offsets: Vec<u8> = Vec::with_capacity(4 * (n + 1));
+                let mut data: Vec<u8> = Vec::new();
+
+                offsets.extend_from_slice(&0i32.to_le_bytes());
+
+                for v in x.iter() {
+                    data.extend_from_slice(v.as_deref().unwrap_or_default().as_bytes());
+                    offsets.extend_from_slice(&(data.len() as i32).to_le_bytes());
+                }
+
+                self.push_buffer(&offsets);
+                self.push_buffer(&data);
+            }
+            Column::Dictionary(x) => {
+                let (indices, _) = dictionary(x);
+                self.push_buffer(&fixed(&indices, i32::to_le_bytes));
+            }
+            Column::Int32(x) | Column::Date(x) => self.push_buffer(&fixed(x, i32::to_le_bytes)),
+            Column::Float64(x) => self.push_buffer(&fixed(x, f64::to_le_bytes)),
+            Column::Timestamp(x) => self.push_buffer(&fixed(x, i64::to_le_bytes)),
+            Column::Boolean(x) => self.push_buffer(&bitmap(x.iter().map(|v| v.unwrap_or(false)))),
+        }
+    }
+
+    fn record_batch(&self, n: usize) -> Table {
+        Table::default()
+            .with(0, Value::I64(n as i64))
+            .with(1, Value::Structs(self.nodes.clone()))
+            .with(2, Value::Structs(self.buffers.clone()))
+    }
+}
+
+// values in little endian, with missing values as zero
+fn fixed<T, const N: usize>(x: &[Option<T>], to_bytes: fn(T) -> [u8; N]) -> Vec<u8>
+where
+    T: Copy + Default,
+{
+    x.iter()
+        .flat_map(|v| to_bytes(v.unwrap_or_default()))
+        .collect()
+}
+
+// least significant bit first
+pub(crate) fn bitmap<I: Iterator<Item = bool>>(x: I) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for (i, b) in x.enumerate() {
+        if i % 8 == 0 {
+            out.push(0);
+        }
+
+        if b {
+            *out.last_mut().unwrap() |= 1 << (i % 8);
+        }
+    }
+
+    out
+}
+
+fn pair(a: i64, b: i64) -> Vec<u8> {
+    [a.to_le_bytes(), b.to_le_bytes()].concat()
+}
+
+// MESSAGES --------------------------------------------------------------------
+fn message(header_type: u8, header: Table, body_length: i64) -> Vec<u8> {
+    finish(
+        Table::default()
+            .with(0, Value::I16(VERSION))
+            .with(1, Value::U8(header_type))
+            .with(2, Value::Table(header))
+            .with(3, Value::I64(body_length)),
+    )
+}
+
+// append a message, i.e. the continuation marker, the length of its metadata,
+// the metadata and the body. returns its Block for the footer: the offset,
+// length of everything before the body, and length of the body.
+fn encapsulate(out: &mut Vec<u8>, meta: Vec<u8>, body: &[u8]) -> Vec<u8> {
+    let offset = out.len();
+
+    out.extend_from_slice(&CONTINUATION);
+    out.extend_from_slice(&(meta.len() as i32).to_le_bytes());
+    out.extend_from_slice(&meta);
+
+    let meta_length = out.len() - offset;
+
+    out.extend_from_slice(body);
+
+    [
+        &(offset as i64).to_le_bytes()[..],
+        &(meta_length as i32).to_le_bytes(),
+        &[0; 4],
+        &(body.len() as i64).to_le_bytes(),
+    ]
+    .concat()
+}
+
+fn pad(x: &mut Vec<u8>, to: usize) {
+    x.resize((x.len() + to - 1) / to * to, 0);
+}
+
+// FLATBUFFERS -----------------------------------------------------------------
+// flatbuffers are usually built back to front, but offsets only have to point
+// forward, so here each table is written before whatever it points to. each
+// table is preceded by its vtable, and every field is written, defaults too.
+// https://flatbuffers.dev/internals/
+#[derive(Clone)]
+enum Value {
+    Bool(bool),
+    U8(u8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    Table(Table),
+    String(String),
+    Tables(Vec<Table>),
+    // fixed size structs, as their bytes, which here are all 8 byte aligned
+    Structs(Vec<Vec<u8>>),
+}
+
+impl Value {
+    // the size (and alignment) of the value inline in its table, where
+    // anything not a scalar is an offset
+    fn size(&self) -> usize {
+        match self {
+            Value::Bool(_) | Value::U8(_) => 1,
+            Value::I16(_) => 2,
+            Value::I64(_) => 8,
+            _ => 4,
+        }
+    }
+}
+
+// fields by slot, i.e. their position in the schema definition
+#[derive(Clone, Default)]
+struct Table {
+    fields: Vec<(usize, Value)>,
+}
+
+impl Table {
+    fn with(mut self, slot: usize, value: Value) -> Self {
+        self.fields.push((slot, value));
+        self
+    }
+}
+
+// a finished buffer starts with the offset to its root table, and is padded
+// to 8 bytes so whatever follows it stays aligned
+fn finish(root: Table) -> Vec<u8> {
+    let mut buf = vec![0; 4];
+    let at = write_table(&mut buf, &root);
+
+    patch(&mut buf, 0, at);
+    pad(&mut buf, 8);
+
+    buf
+}
+
+// point the offset at `at` to `to`
+fn patch(buf: &mut [u8], at: usize, to: usize) {
+    buf[at..at + 4].copy_from_slice(&((to - at) as u32).to_le_bytes());
+}
+
+fn write_table(buf: &mut Vec<u8>, table: &Table) -> usize {
+    // lay the fields out largest first, after the offset to the vtable
+    let mut order: Vec<&(usize, Value)> = table.fields.iter().collect();
+    order.sort_by_key(|(_, v)| std::cmp::Reverse(v.size()));
+
+    let mut size = 4;
+    let mut positions = Vec::with_capacity(order.len());
+
+    for (_, v) in order.iter() {
+        size = (size + v.size() - 1) / v.size() * v.size();
+        positions.push(size);
+        size += v.size();
+    }
+
+    let align = order.first().map_or(4, |(_, v)| v.size().max(4));
+    let n_slots = table.fields.iter().map(|(s, _)| s + 1).max().unwrap_or(0);
+
+    // vtable: its own size, the table's size, then each slot's position in
+    // the table, or zero if absent
+    pad(buf, 2);
+    let vtable = buf.len();
+    let mut slots = vec![0u16; n_slots];
+
+    for ((slot, _), &p) in order.iter().zip(positions.iter()) {
+        slots[*slot] = p as u16;
+    }
+
+    buf.extend_from_slice(&((4 + 2 * n_slots) as u16).to_le_bytes());
+    buf.extend_from_slice(&(size as u16).to_le_bytes());
+
+    for s in slots {
+        buf.extend_from_slice(&s.to_le_bytes());
+    }
+
+    pad(buf, align);
+    let start = buf.len();
+
+    buf.resize(start + size, 0);
+    buf[start..start + 4].copy_from_slice(&((start - vtable) as i32).to_le_bytes());
+
+    let mut children = Vec::new();
+
+    for ((_, v), &p) in order.iter().zip(positions.iter()) {
+        let at = start + p;
+
+        match v {
+            Value::Bool(x) => buf[at] = *x as u8,
+            Value::U8(x) => buf[at] = *x,
+            Value::I16(x) => buf[at..at + 2].copy_from_slice(&x.to_le_bytes()),
+            Value::I32(x) => buf[at..at + 4].copy_from_slice(&x.to_le_bytes()),
+            Value::I64(x) => buf[at..at + 8].copy_from_slice(&x.to_le_bytes()),
+            _ => children.push((at, v)),
+        }
+    }
+
+    for (at, v) in children {
+        let to = write_value(buf, v);
+        patch(buf, at, to);
+    }
+
+    start
+}
+
+fn write_value(buf: &mut Vec<u8>, value: &Value) -> usize {
+    match value {
+        Value::Table(t) => write_table(buf, t),
+        Value::String(s) => {
+            pad(buf, 4);
+            let start = buf.len();
+
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+
+            start
+        }
+        Value::Tables(tables) => {
+            pad(buf, 4);
+            let start = buf.len();
+
+            buf.extend_from_slice(&(tables.len() as u32).to_le_bytes());
+            buf.resize(start + 4 + 4 * tables.len(), 0);
+
+            for (i, t) in tables.iter().enumerate() {
+                let to = write_table(buf, t);
+                patch(buf, start + 4 + 4 * i, to);
+            }
+
+            start
+        }
+        Value::Structs(structs) => {
+            // the length goes just before the first 8 byte boundary
+            pad(buf, 4);
+
+            if buf.len() % 8 == 0 {
+                buf.extend_from_slice(&[0; 4]);
+            }
+
+            let start = buf.len();
+
+            buf.extend_from_slice(&(structs.len() as u32).to_le_bytes());
+
+            for s in structs.iter() {
+                buf.extend_from_slice(s);
+            }
+
+            start
+        }
+        _ => unreachable!("scalars are written inline"),
+    }
+}
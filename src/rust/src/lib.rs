@@ -1,33 +1,167 @@
 use extendr_api::prelude::*;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::BTreeMap;
 
+mod aoi;
+mod arrow;
+mod batch;
+mod cache;
+mod cassette;
+mod dates;
+mod de;
+mod element;
+mod errors;
+mod fetch;
+mod geometry;
+mod http;
+mod huc;
+mod ipc;
+mod nearest;
+mod normals;
+mod parquet;
+mod shef;
+mod triplet;
+mod units;
+mod update;
+
+use aoi::Aoi;
+use arrow::Output;
+use batch::Batch;
+use cache::Cache;
+use cassette::Cassette;
+use element::ElementTriplet;
+use errors::{ParseDiagnostics, ParseError, RequestError, RequestFailures, UnitError};
+use fetch::{Client, Fetched, Parts, Request};
+use geometry::Geometry;
+use huc::{HucFilter, StationHucs};
+use nearest::StationFilter;
+use normals::NormalsOptions;
+use shef::ShefCodes;
+use std::collections::{HashMap, HashSet};
+use triplet::StationTriplet;
+use units::{Conversion, UnitTarget};
+
 // https://wcc.sc.egov.usda.gov/awdbRestApi/swagger-ui/index.html
 
+// mean earth radius, in km, for distances to and between stations
+const EARTH_RADIUS: f64 = 6371.0088;
+
 // STATION DATA ----------------------------------------------------------------
-// each station dataset is a row in the data frame
-// want to move StationElement variables up to this top level,
-// and let Values be a list column with a data frame in each row
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct StationDataSet(Vec<StationData>);
+// each station element is a row in the data frame, with the station triplet
+// moved up to this top level and Values as a list column with a data frame in
+// each row. Values are pushed straight into columns as the json is read (see
+// `de.rs`), so there is never a second copy of the data held in rows.
+#[derive(Debug, Clone)]
+struct StationDataSet {
+    station_triplet: Vec<StationTriplet>,
+    station_element: Vec<StationElement>,
+    // values for station element i are offsets[i]..offsets[i + 1]
+    offsets: Vec<usize>,
+    values: ValueColumns,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct StationData {
-    station_triplet: String,
-    data: Vec<ElementData>,
+impl Default for StationDataSet {
+    fn default() -> Self {
+        StationDataSet {
+            station_triplet: Vec::new(),
+            station_element: Vec::new(),
+            offsets: vec![0],
+            values: ValueColumns::default(),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "camelCase")]
-struct ElementData {
-    station_element: StationElement,
-    values: Vec<Values>,
+impl StationDataSet {
+    // stitch together the chunks parsed from each json string, in order
+    fn concat(chunks: Vec<StationDataSet>) -> Self {
+        let mut sd = StationDataSet::default();
+
+        for mut chunk in chunks.into_iter() {
+            let base = sd.values.len();
+
+            sd.station_triplet.append(&mut chunk.station_triplet);
+            sd.station_element.append(&mut chunk.station_element);
+            sd.offsets
+                .extend(chunk.offsets.iter().skip(1).map(|o| o + base));
+            sd.values.append(&mut chunk.values);
+        }
+
+        sd
+    }
+
+    // a long date range is requested in windows, so the same station element
+    // comes back once per window. fold those back into one station element
+    // with the metadata of its first window, widened to the earliest begin
+    // and latest end date seen, and its values in window order. windows end
+    // the day before the next begins, with hourly windows ending at 23:59, so
+    // they shouldn't share any values, but one seen twice is only kept the
+    // first time.
+    fn merge_windows(self) -> Self {
+        let mut rows: Vec<Vec<usize>> = Vec::new();
+        let mut index: HashMap<(String, &str, i32, Option<i32>, &str), usize> = HashMap::new();
+
+        for (i, se) in self.station_element.iter().enumerate() {
+            let key = (
+                self.station_triplet[i].to_string(),
+                se.element_code.as_str(),
+                se.ordinal,
+                se.height_depth,
+                se.duration_name.as_str(),
+            );
+
+            match index.get(&key) {
+                Some(&row) => rows[row].push(i),
+                None => {
+                    index.insert(key, rows.len());
+                    rows.push(vec![i]);
+                }
+            }
+        }
+
+        if rows.len() == self.station_element.len() {
+            return self;
+        }
+
+        let mut sd = StationDataSet::default();
+        let mut keep: Vec<usize> = Vec::with_capacity(self.values.len());
+
+        for row in rows.iter() {
+            let mut se = self.station_element[row[0]].clone();
+            let mut seen = HashSet::new();
+
+            for &i in row.iter() {
+                let other = &self.station_element[i];
+
+                if other.begin_date < se.begin_date {
+                    se.begin_date = other.begin_date.clone();
+                }
+
+                if other.end_date > se.end_date {
+                    se.end_date = other.end_date.clone();
+                }
+
+                for j in self.offsets[i]..self.offsets[i + 1] {
+                    if seen.insert(self.values.key(j)) {
+                        keep.push(j);
+                    }
+                }
+            }
+
+            sd.station_triplet
+                .push(self.station_triplet[row[0]].clone());
+            sd.station_element.push(se);
+            sd.offsets.push(keep.len());
+        }
+
+        sd.values = self.values.select(&keep);
+
+        sd
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, IntoDataFrameRow)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct StationElement {
     element_code: String,
@@ -42,7 +176,7 @@ struct StationElement {
     derived_data: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, IntoDataFrameRow)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct Values {
     date: Option<String>,
@@ -56,14 +190,225 @@ struct Values {
     orig_value: Option<f64>,
     orig_qc_flag: Option<String>,
     average: Option<f64>,
-    median: Option<i32>,
+    median: Option<f64>,
+}
+
+// dates are held as seconds since the epoch, in the station's local time until
+// `apply_time_zones()` shifts hourly values to UTC
+#[derive(Debug, Clone, Default)]
+struct ValueColumns {
+    date: Vec<Option<f64>>,
+    month: Vec<Option<i32>>,
+    month_part: Vec<Option<String>>,
+    year: Vec<Option<i32>>,
+    collection_date: Vec<Option<f64>>,
+    value: Vec<Option<f64>>,
+    qc_flag: Vec<Option<String>>,
+    qa_flag: Vec<Option<String>>,
+    orig_value: Vec<Option<f64>>,
+    orig_qc_flag: Vec<Option<String>>,
+    average: Vec<Option<f64>>,
+    median: Vec<Option<f64>>,
+}
+
+impl ValueColumns {
+    fn len(&self) -> usize {
+        self.date.len()
+    }
+
+    fn push(&mut self, v: Values) {
+        self.date
+            .push(v.date.as_deref().and_then(dates::parse_datetime));
+        self.month.push(v.month);
+        self.month_part.push(v.month_part);
+        self.year.push(v.year);
+        self.collection_date
+            .push(v.collection_date.as_deref().and_then(dates::parse_datetime));
+        self.value.push(v.value);
+        self.qc_flag.push(v.qc_flag);
+        self.qa_flag.push(v.qa_flag);
+        self.orig_value.push(v.orig_value);
+        self.orig_qc_flag.push(v.orig_qc_flag);
+        self.average.push(v.average);
+        self.median.push(v.median);
+    }
+
+    fn append(&mut self, other: &mut ValueColumns) {
+        self.date.append(&mut other.date);
+        self.month.append(&mut other.month);
+        self.month_part.append(&mut other.month_part);
+        self.year.append(&mut other.year);
+        self.collection_date.append(&mut other.collection_date);
+        self.value.append(&mut other.value);
+        self.qc_flag.append(&mut other.qc_flag);
+        self.qa_flag.append(&mut other.qa_flag);
+        self.orig_value.append(&mut other.orig_value);
+        self.orig_qc_flag.append(&mut other.orig_qc_flag);
+        self.average.append(&mut other.average);
+        self.median.append(&mut other.median);
+    }
+
+    // what identifies a value within its station element: its date, or for
+    // durations without one, its year, month and month part
+    fn key(&self, i: usize) -> (Option<u64>, Option<i32>, Option<i32>, Option<&str>) {
+        (
+            self.date[i].map(f64::to_bits),
+            self.year[i],
+            self.month[i],
+            self.month_part[i].as_deref(),
+        )
+    }
+
+    fn select(&self, rows: &[usize]) -> ValueColumns {
+        fn pick<T: Clone>(x: &[T], rows: &[usize]) -> Vec<T> {
+            rows.iter().map(|&i| x[i].clone()).collect()
+        }
+
+        ValueColumns {
+            date: pick(&self.date, rows),
+            month: pick(&self.month, rows),
+            month_part: pick(&self.month_part, rows),
+            year: pick(&self.year, rows),
+            collection_date: pick(&self.collection_date, rows),
+            value: pick(&self.value, rows),
+            qc_flag: pick(&self.qc_flag, rows),
+            qa_flag: pick(&self.qa_flag, rows),
+            orig_value: pick(&self.orig_value, rows),
+            orig_qc_flag: pick(&self.orig_qc_flag, rows),
+            average: pick(&self.average, rows),
+            median: pick(&self.median, rows),
+        }
+    }
+
+    fn split_off(&mut self, at: usize) -> ValueColumns {
+        ValueColumns {
+            date: self.date.split_off(at),
+            month: self.month.split_off(at),
+            month_part: self.month_part.split_off(at),
+            year: self.year.split_off(at),
+            collection_date: self.collection_date.split_off(at),
+            value: self.value.split_off(at),
+            qc_flag: self.qc_flag.split_off(at),
+            qa_flag: self.qa_flag.split_off(at),
+            orig_value: self.orig_value.split_off(at),
+            orig_qc_flag: self.orig_qc_flag.split_off(at),
+            average: self.average.split_off(at),
+            median: self.median.split_off(at),
+        }
+    }
+}
+
+impl ValueColumns {
+    // hourly values become POSIXct, everything coarser becomes Date
+    fn into_robj(self, hourly: bool) -> Robj {
+        let v = self;
+
+        let mut df = data_frame!(
+            date = date_column(v.date, hourly),
+            month = v.month,
+            month_part = v.month_part,
+            year = v.year,
+            collection_date = date_column(v.collection_date, false),
+            value = v.value,
+            qc_flag = v.qc_flag,
+            qa_flag = v.qa_flag,
+            orig_value = v.orig_value,
+            orig_qc_flag = v.orig_qc_flag,
+            average = v.average,
+            median = v.median
+        );
+
+        df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+        drop_empty_columns(&df).unwrap()
+    }
+}
+
+fn date_column(seconds: Vec<Option<f64>>, hourly: bool) -> Robj {
+    if hourly {
+        dates::datetime_robj(seconds)
+    } else {
+        dates::date_robj(seconds.into_iter().map(dates::seconds_to_days).collect())
+    }
+}
+
+impl StationElement {
+    fn is_hourly(&self) -> bool {
+        self.duration_name == "HOURLY"
+    }
+}
+
+impl StationDataSet {
+    // hourly values are reported in local standard time, so shift them by the
+    // station's `data_time_zone` (hours from UTC) to get true UTC instants.
+    // stations with no known offset are left as they are, i.e. treated as UTC.
+    fn apply_time_zones(&mut self, time_zones: &HashMap<String, f64>) {
+        for (i, se) in self.station_element.iter().enumerate() {
+            if !se.is_hourly() {
+                continue;
+            }
+
+            let Some(offset) = time_zones.get(&self.station_triplet[i].to_string()) else {
+                continue;
+            };
+
+            for d in self.values.date[self.offsets[i]..self.offsets[i + 1]].iter_mut() {
+                *d = d.map(|s| s - offset * 3600.0);
+            }
+        }
+    }
+
+    // rewrite `value`, `average`, `median` and `orig_value` from each element's
+    // stored unit into the target unit, and `stored_unit_code` to match.
+    // `original_unit_code` is left alone since it only records how the data
+    // was first collected.
+    fn convert_units(&mut self, target: &UnitTarget) -> std::result::Result<(), UnitError> {
+        for (i, se) in self.station_element.iter_mut().enumerate() {
+            let at = |e: UnitError| e.at(&se.element_code, &self.station_triplet[i].to_string());
+
+            let to = target
+                .target_code(&se.element_code, &se.stored_unit_code)
+                .map_err(at)?
+                .to_string();
+            let conversion = Conversion::new(&se.stored_unit_code, &to).map_err(at)?;
+
+            if conversion.is_identity() {
+                continue;
+            }
+
+            let range = self.offsets[i]..self.offsets[i + 1];
+            let v = &mut self.values;
+
+            for x in v.value[range.clone()]
+                .iter_mut()
+                .chain(v.average[range.clone()].iter_mut())
+                .chain(v.median[range.clone()].iter_mut())
+                .chain(v.orig_value[range].iter_mut())
+            {
+                *x = conversion.apply(*x);
+            }
+
+            se.stored_unit_code = to;
+        }
+
+        Ok(())
+    }
 }
 
 impl From<StationDataSet> for Robj {
-    fn from(sd: StationDataSet) -> Self {
-        let n_row = sd.0.iter().map(|x| x.data.len()).sum();
+    fn from(mut sd: StationDataSet) -> Self {
+        let n_row = sd.station_element.len();
+
+        // split values off the back so each split only moves one element's worth
+        let mut values: Vec<Robj> = Vec::with_capacity(n_row);
+
+        for i in (0..n_row).rev() {
+            let hourly = sd.station_element[i].is_hourly();
+            values.push(sd.values.split_off(sd.offsets[i]).into_robj(hourly));
+        }
+
+        values.reverse();
 
-        let mut station_triplet: Vec<String> = Vec::with_capacity(n_row);
         let mut element_code: Vec<String> = Vec::with_capacity(n_row);
         let mut ordinal: Vec<i32> = Vec::with_capacity(n_row);
         let mut height_depth: Vec<Option<i32>> = Vec::with_capacity(n_row);
@@ -71,49 +416,95 @@ impl From<StationDataSet> for Robj {
         let mut data_precision: Vec<i32> = Vec::with_capacity(n_row);
         let mut stored_unit_code: Vec<String> = Vec::with_capacity(n_row);
         let mut original_unit_code: Vec<String> = Vec::with_capacity(n_row);
-        let mut begin_date: Vec<String> = Vec::with_capacity(n_row);
-        let mut end_date: Vec<String> = Vec::with_capacity(n_row);
+        let mut begin_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
+        let mut end_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
         let mut derived_data: Vec<bool> = Vec::with_capacity(n_row);
-        let mut values: Vec<Robj> = Vec::with_capacity(n_row);
 
-        for x in sd.0.into_iter() {
-            for y in x.data.into_iter() {
-                let StationElement {
-                    element_code: ec,
-                    ordinal: o,
-                    height_depth: hd,
-                    duration_name: dn,
-                    data_precision: dp,
-                    stored_unit_code: suc,
-                    original_unit_code: ouc,
-                    begin_date: bd,
-                    end_date: ed,
-                    derived_data: dd,
-                } = y.station_element;
-
-                station_triplet.push(x.station_triplet.clone());
-                element_code.push(ec);
-                ordinal.push(o);
-                height_depth.push(hd);
-                duration_name.push(dn);
-                data_precision.push(dp);
-                stored_unit_code.push(suc);
-                original_unit_code.push(ouc);
-                begin_date.push(bd);
-                end_date.push(ed);
-                derived_data.push(dd);
+        for se in sd.station_element.into_iter() {
+            element_code.push(se.element_code);
+            ordinal.push(se.ordinal);
+            height_depth.push(se.height_depth);
+            duration_name.push(se.duration_name);
+            data_precision.push(se.data_precision);
+            stored_unit_code.push(se.stored_unit_code);
+            original_unit_code.push(se.original_unit_code);
+            begin_date.push(dates::parse_date(&se.begin_date));
+            end_date.push(dates::parse_date(&se.end_date));
+            derived_data.push(se.derived_data);
+        }
 
-                let mut values_df = y.values.into_dataframe().unwrap().into_robj();
-                values_df
-                    .set_class(&["tbl_df", "tbl", "data.frame"])
-                    .unwrap();
+        let mut values = List::from_values(values);
+        values.set_class(&["AsIs"]).unwrap();
+
+        let mut df = data_frame!(
+            station_triplet = sd
+                .station_triplet
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            element_code = element_code,
+            ordinal = ordinal,
+            height_depth = height_depth,
+            duration_name = duration_name,
+            data_precision = data_precision,
+            stored_unit_code = stored_unit_code,
+            original_unit_code = original_unit_code,
+            begin_date = dates::date_robj(begin_date),
+            end_date = dates::date_robj(end_date),
+            derived_data = derived_data,
+            element_values = values
+        );
+
+        df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+        drop_empty_columns(&df).unwrap()
+    }
+}
+
+// long format has one row per value instead of one row per station element,
+// repeating the station element variables alongside each value, so no
+// nested tibbles need to be built
+impl StationDataSet {
+    fn into_long(self) -> Robj {
+        let n_row = self.values.len();
+        let triplets: Vec<String> = self.station_triplet.iter().map(|t| t.to_string()).collect();
+
+        let mut station_triplet: Vec<&str> = Vec::with_capacity(n_row);
+        let mut element_code: Vec<&str> = Vec::with_capacity(n_row);
+        let mut ordinal: Vec<i32> = Vec::with_capacity(n_row);
+        let mut height_depth: Vec<Option<i32>> = Vec::with_capacity(n_row);
+        let mut duration_name: Vec<&str> = Vec::with_capacity(n_row);
+        let mut data_precision: Vec<i32> = Vec::with_capacity(n_row);
+        let mut stored_unit_code: Vec<&str> = Vec::with_capacity(n_row);
+        let mut original_unit_code: Vec<&str> = Vec::with_capacity(n_row);
+        let mut begin_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
+        let mut end_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
+        let mut derived_data: Vec<bool> = Vec::with_capacity(n_row);
 
-                values.push(drop_empty_columns(&values_df).unwrap());
+        for (i, se) in self.station_element.iter().enumerate() {
+            let n = self.offsets[i + 1] - self.offsets[i];
+            let bd = dates::parse_date(&se.begin_date);
+            let ed = dates::parse_date(&se.end_date);
+
+            for _ in 0..n {
+                station_triplet.push(&triplets[i]);
+                element_code.push(&se.element_code);
+                ordinal.push(se.ordinal);
+                height_depth.push(se.height_depth);
+                duration_name.push(&se.duration_name);
+                data_precision.push(se.data_precision);
+                stored_unit_code.push(&se.stored_unit_code);
+                original_unit_code.push(&se.original_unit_code);
+                begin_date.push(bd);
+                end_date.push(ed);
+                derived_data.push(se.derived_data);
             }
         }
 
-        let mut values = List::from_values(values);
-        values.set_class(&["AsIs"]).unwrap();
+        // one date column has to serve every element, so it is POSIXct if any
+        // of them are hourly, with coarser values falling at midnight UTC
+        let hourly = self.station_element.iter().any(|se| se.is_hourly());
+        let v = self.values;
 
         let mut df = data_frame!(
             station_triplet = station_triplet,
@@ -124,26 +515,154 @@ impl From<StationDataSet> for Robj {
             data_precision = data_precision,
             stored_unit_code = stored_unit_code,
             original_unit_code = original_unit_code,
-            begin_date = begin_date,
-            end_date = end_date,
+            begin_date = dates::date_robj(begin_date),
+            end_date = dates::date_robj(end_date),
             derived_data = derived_data,
-            element_values = values
+            date = date_column(v.date, hourly),
+            month = v.month,
+            month_part = v.month_part,
+            year = v.year,
+            collection_date = date_column(v.collection_date, false),
+            value = v.value,
+            qc_flag = v.qc_flag,
+            qa_flag = v.qa_flag,
+            orig_value = v.orig_value,
+            orig_qc_flag = v.orig_qc_flag,
+            average = v.average,
+            median = v.median
         );
 
         df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
 
         drop_empty_columns(&df).unwrap()
     }
+
+    fn into_robj_as(self, long: bool) -> Robj {
+        if long {
+            self.into_long()
+        } else {
+            self.into()
+        }
+    }
 }
 
+// how to finish a station data set once its chunks are parsed. `time_zones`
+// is a numeric vector of `data_time_zone` offsets named by station triplet,
+// taken from the station metadata. `units` is NULL to keep stored units, "si",
+// or unit codes named by element code. `hucs` is NULL, or the HUC of each
+// station named by station triplet to add `huc2` through `huc12` columns. with
+// an `output`, the data set is written to a file instead of returned.
+struct StationDataOptions {
+    lenient: bool,
+    long: bool,
+    time_zones: HashMap<String, f64>,
+    units: Option<UnitTarget>,
+    triplet_columns: bool,
+    hucs: Option<StationHucs>,
+    output: Option<Output>,
+}
+
+impl StationDataOptions {
+    fn new(
+        lenient: bool,
+        long: bool,
+        time_zones: Robj,
+        units: Robj,
+        triplet_columns: bool,
+        hucs: Robj,
+        output: Option<Output>,
+    ) -> Self {
+        StationDataOptions {
+            lenient,
+            long,
+            time_zones: named_doubles(&time_zones),
+            units: UnitTarget::from_robj(&units),
+            triplet_columns,
+            hucs: StationHucs::from_robj(&hucs),
+            output,
+        }
+    }
+}
+
+fn station_dataset_robj<F>(
+    results: Vec<Chunk<StationDataSet>>,
+    station_triplets: F,
+    opts: &StationDataOptions,
+) -> Robj
+where
+    F: Fn(usize) -> String,
+{
+    let (chunks, diagnostics) = match collect_chunks(results, opts.lenient, station_triplets) {
+        Ok(x) => x,
+        Err(e) => return e.into(),
+    };
+
+    let mut sd = StationDataSet::concat(chunks).merge_windows();
+
+    sd.apply_time_zones(&opts.time_zones);
+
+    if let Some(target) = &opts.units {
+        if let Err(e) = sd.convert_units(target) {
+            return e.into();
+        }
+    }
+
+    let df = match &opts.output {
+        Some(output) => output.write(&sd.record_batch(opts.triplet_columns, opts.hucs.as_ref())),
+        None => with_station_columns(
+            sd.into_robj_as(opts.long),
+            opts.triplet_columns,
+            opts.hucs.as_ref(),
+        ),
+    };
+
+    match diagnostics {
+        Some(diagnostics) => with_diagnostics(df, diagnostics),
+        None => df,
+    }
+}
+
+// `batches` are comma separated station triplets, one request each, and
+// `query` is a named character vector of the remaining query parameters.
+// `output` is NULL to return a data frame, or a character vector with the
+// `path` and `format` ("arrow" or "parquet") of a file to write instead.
 #[extendr]
-fn parse_station_dataset_json(x: Strings) -> Robj {
-    let vec_data = x
-        .into_iter()
-        .flat_map(|v| serde_json::from_str::<StationDataSet>(v).unwrap().0)
-        .collect();
+#[allow(clippy::too_many_arguments)]
+fn fetch_station_dataset(
+    base_url: &str,
+    batches: Robj,
+    query: Robj,
+    lenient: bool,
+    threads: usize,
+    long: bool,
+    time_zones: Robj,
+    units: Robj,
+    triplet_columns: bool,
+    hucs: Robj,
+    cache_dir: Robj,
+    cassette: Robj,
+    output: Robj,
+) -> Robj {
+    let opts = StationDataOptions::new(
+        lenient,
+        long,
+        time_zones,
+        units,
+        triplet_columns,
+        hucs,
+        Output::from_robj(&output),
+    );
+    let batches = batch::batches_from_robj(&batches);
+    let query = named_strings(&query);
+    let request = batch_request(base_url, "data", &query, &batches);
+
+    let fetched = match fetch_batches(&batches, request, &cache_dir, &cassette, threads) {
+        Ok(fetched) => fetched,
+        Err(e) => return e.into(),
+    };
+    let df = station_dataset_robj(fetched.results, |i| fetched.stations[i].clone(), &opts);
 
-    StationDataSet(vec_data).into()
+    with_failures(df, fetched.failures)
 }
 
 // STATION FORECAST ------------------------------------------------------------
@@ -155,7 +674,7 @@ struct StationForecastSet(Vec<StationForecast>);
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 struct StationForecast {
-    station_triplet: String,
+    station_triplet: StationTriplet,
     forecast_point_name: String,
     data: Vec<Forecast>,
 }
@@ -170,45 +689,155 @@ struct Forecast {
     period_normal: Option<f64>,
     publication_date: String,
     unit_code: String,
-    forecast_values: BTreeMap<String, Value>,
+    forecast_values: Option<BTreeMap<String, Value>>,
+}
+
+// forecast periods come as ["04-01", "07-31"], i.e. begin and end month-day.
+// the code, e.g. "APR-JUL", is looked up in the forecastPeriods reference
+// list by those month-days, and is missing for periods not in it.
+struct ForecastPeriod {
+    code: Option<String>,
+    begin: Option<String>,
+    end: Option<String>,
+    begin_date: Option<f64>,
+    end_date: Option<f64>,
 }
 
-impl From<StationForecastSet> for Robj {
-    fn from(sf: StationForecastSet) -> Self {
-        let n_row = sf.0.len();
+// a month and day, e.g. (4, 1) for "04-01"
+type MonthDay = (u32, u32);
+
+// the code of each forecast period in the reference list, by its begin and
+// end month-day
+#[derive(Debug, Default)]
+struct ForecastPeriods(HashMap<(MonthDay, MonthDay), String>);
+
+impl ForecastPeriods {
+    // from R: NULL, or a character vector of codes named by their begin and
+    // end month-day, e.g. c("04-01 07-31" = "APR-JUL")
+    fn from_robj(x: &Robj) -> Self {
+        let codes = named_strings(x)
+            .into_iter()
+            .filter_map(|(period, code)| {
+                let (begin, end) = period.split_once(' ')?;
+                let begin = dates::parse_month_day(begin)?;
+                let end = dates::parse_month_day(end)?;
+
+                Some(((begin, end), code))
+            })
+            .collect();
+
+        ForecastPeriods(codes)
+    }
+
+    fn code(&self, begin: Option<MonthDay>, end: Option<MonthDay>) -> Option<String> {
+        self.0.get(&(begin?, end?)).cloned()
+    }
+}
+
+impl ForecastPeriod {
+    fn new(period: &[String], issue_date: &str, periods: &ForecastPeriods) -> Self {
+        let begin = period.first().cloned();
+        let end = period.last().cloned();
+
+        let begin_md = begin.as_deref().and_then(dates::parse_month_day);
+        let end_md = end.as_deref().and_then(dates::parse_month_day);
+
+        let code = periods.code(begin_md, end_md);
+
+        // concrete dates for the water year the forecast was issued in
+        let water_year = dates::water_year(issue_date);
+
+        let begin_date = water_year
+            .zip(begin_md)
+            .map(|(wy, (m, d))| dates::water_year_date(wy, m, d));
+
+        let end_date = water_year
+            .zip(end_md)
+            .map(|(wy, (m, d))| dates::water_year_date(wy, m, d));
+
+        ForecastPeriod {
+            code,
+            begin,
+            end,
+            begin_date,
+            end_date,
+        }
+    }
+}
+
+// exceedance probabilities come as json keys, so parse them to numbers and
+// sort numerically ("10" after "5"). values can be numbers, numeric strings, or
+// null when an exceedance level hasn't been issued yet.
+fn parse_forecast_values(
+    x: Option<BTreeMap<String, Value>>,
+) -> (Vec<Option<f64>>, Vec<Option<f64>>) {
+    let mut fv: Vec<(Option<f64>, Option<f64>)> = x
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(k, v)| (k.trim().parse::<f64>().ok(), value_as_f64(&v)))
+        .collect();
+
+    fv.sort_by(|a, b| match (a.0, b.0) {
+        (Some(x), Some(y)) => x.total_cmp(&y),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+
+    fv.into_iter().unzip()
+}
+
+fn value_as_f64(x: &Value) -> Option<f64> {
+    match x {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+impl StationForecastSet {
+    fn into_robj_with(self, periods: &ForecastPeriods) -> Robj {
+        let n_row = self.0.len();
 
         let mut station_triplet: Vec<String> = Vec::with_capacity(n_row);
         let mut forecast_point_name: Vec<String> = Vec::with_capacity(n_row);
         let mut element_code: Vec<String> = Vec::with_capacity(n_row);
-        let mut forecast_period: Vec<String> = Vec::with_capacity(n_row);
+        let mut forecast_period: Vec<Option<String>> = Vec::with_capacity(n_row);
+        let mut period_begin: Vec<Option<String>> = Vec::with_capacity(n_row);
+        let mut period_end: Vec<Option<String>> = Vec::with_capacity(n_row);
+        let mut period_begin_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
+        let mut period_end_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
         let mut forecast_status: Vec<String> = Vec::with_capacity(n_row);
-        let mut issue_date: Vec<String> = Vec::with_capacity(n_row);
+        let mut issue_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
         let mut period_normal: Vec<Option<f64>> = Vec::with_capacity(n_row);
-        let mut publication_date: Vec<String> = Vec::with_capacity(n_row);
+        let mut publication_date: Vec<Option<f64>> = Vec::with_capacity(n_row);
         let mut unit_code: Vec<String> = Vec::with_capacity(n_row);
         let mut forecast_values: Vec<Robj> = Vec::with_capacity(n_row);
 
-        for x in sf.0.into_iter() {
+        for x in self.0.into_iter() {
             for y in x.data.into_iter() {
-                station_triplet.push(x.station_triplet.clone());
+                station_triplet.push(x.station_triplet.to_string());
                 forecast_point_name.push(x.forecast_point_name.clone());
                 element_code.push(y.element_code);
-                forecast_period.push(y.forecast_period.join(":"));
+                let fp = ForecastPeriod::new(&y.forecast_period, &y.issue_date, periods);
+                forecast_period.push(fp.code);
+                period_begin.push(fp.begin);
+                period_end.push(fp.end);
+                period_begin_date.push(fp.begin_date);
+                period_end_date.push(fp.end_date);
+
                 forecast_status.push(y.forecast_status);
-                issue_date.push(y.issue_date);
+                issue_date.push(dates::parse_date(&y.issue_date));
                 period_normal.push(y.period_normal);
-                publication_date.push(y.publication_date);
+                publication_date.push(dates::parse_date(&y.publication_date));
                 unit_code.push(y.unit_code);
 
-                let keys: Strings = y.forecast_values.keys().cloned().collect();
-                let values: Vec<f64> = y
-                    .forecast_values
-                    .values()
-                    .map(|v| v.as_f64().unwrap())
-                    .collect();
-                let mut df = data_frame!(probability = keys, value = values);
+                // no dropping empty columns here, every forecast should unnest
+                // to the same probability and value columns even if all NA
+                let (probability, value) = parse_forecast_values(y.forecast_values);
+                let mut df = data_frame!(probability = probability, value = value);
                 df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
-                forecast_values.push(drop_empty_columns(&df).unwrap());
+                forecast_values.push(df);
             }
         }
 
@@ -221,10 +850,14 @@ impl From<StationForecastSet> for Robj {
             forecast_point_name = forecast_point_name,
             element_code = element_code,
             forecast_period = forecast_period,
+            period_begin = period_begin,
+            period_end = period_end,
+            period_begin_date = dates::date_robj(period_begin_date),
+            period_end_date = dates::date_robj(period_end_date),
             forecast_status = forecast_status,
-            issue_date = issue_date,
+            issue_date = dates::date_robj(issue_date),
             period_normal = period_normal,
-            publication_date = publication_date,
+            publication_date = dates::date_robj(publication_date),
             unit_code = unit_code,
             forecast_values = forecast_values
         );
@@ -235,14 +868,335 @@ impl From<StationForecastSet> for Robj {
     }
 }
 
+fn station_forecast_set_robj<F>(
+    results: Vec<Chunk<StationForecastSet>>,
+    station_triplets: F,
+    lenient: bool,
+    triplet_columns: bool,
+    hucs: Option<StationHucs>,
+    periods: ForecastPeriods,
+    output: Option<Output>,
+) -> Robj
+where
+    F: Fn(usize) -> String,
+{
+    let (chunks, diagnostics) = match collect_chunks(results, lenient, station_triplets) {
+        Ok(x) => x,
+        Err(e) => return e.into(),
+    };
+
+    let sf = StationForecastSet(chunks.into_iter().flat_map(|v| v.0).collect());
+
+    let df = match output {
+        Some(output) => output.write(&sf.record_batch(triplet_columns, hucs.as_ref(), &periods)),
+        None => with_station_columns(sf.into_robj_with(&periods), triplet_columns, hucs.as_ref()),
+    };
+
+    match diagnostics {
+        Some(diagnostics) => with_diagnostics(df, diagnostics),
+        None => df,
+    }
+}
+
 #[extendr]
-fn parse_station_forecast_set_json(x: Strings) -> Robj {
-    let vec_data = x
-        .into_iter()
-        .flat_map(|v| serde_json::from_str::<StationForecastSet>(v).unwrap().0)
+#[allow(clippy::too_many_arguments)]
+fn fetch_station_forecast_set(
+    base_url: &str,
+    batches: Robj,
+    query: Robj,
+    lenient: bool,
+    threads: usize,
+    triplet_columns: bool,
+    hucs: Robj,
+    forecast_periods: Robj,
+    cache_dir: Robj,
+    cassette: Robj,
+    output: Robj,
+) -> Robj {
+    let batches = batch::batches_from_robj(&batches);
+    let query = named_strings(&query);
+    let request = batch_request(base_url, "forecasts", &query, &batches);
+
+    let fetched = match fetch_batches(&batches, request, &cache_dir, &cassette, threads) {
+        Ok(fetched) => fetched,
+        Err(e) => return e.into(),
+    };
+    let df = station_forecast_set_robj(
+        fetched.results,
+        |i| fetched.stations[i].clone(),
+        lenient,
+        triplet_columns,
+        StationHucs::from_robj(&hucs),
+        ForecastPeriods::from_robj(&forecast_periods),
+        Output::from_robj(&output),
+    );
+
+    with_failures(df, fetched.failures)
+}
+
+// STATION TRIPLETS ------------------------------------------------------------
+// build and validate triplets from their parts, recycling length one parts,
+// e.g. ("*", "*", c("SNTL", "SCAN")) gives "*:*:SNTL" and "*:*:SCAN"
+#[extendr]
+fn build_station_triplets(station_id: Strings, state_code: Strings, network_code: Strings) -> Robj {
+    let n = station_id
+        .len()
+        .max(state_code.len())
+        .max(network_code.len());
+    let part = |x: &Strings, i: usize| -> String {
+        if x.len() == 1 {
+            x.elt(0).to_string()
+        } else {
+            x.elt(i).to_string()
+        }
+    };
+
+    let mut triplets: Vec<String> = Vec::with_capacity(n);
+
+    for i in 0..n {
+        match StationTriplet::new(
+            &part(&station_id, i),
+            &part(&state_code, i),
+            &part(&network_code, i),
+        ) {
+            Ok(t) => triplets.push(t.to_string()),
+            Err(e) => return e.into(),
+        }
+    }
+
+    triplets.into_robj()
+}
+
+// ELEMENT TRIPLETS ------------------------------------------------------------
+// parse and normalize element triplets, e.g. "sms:-8" becomes "SMS:-8".
+// `codes` and `names` come from the elements reference list; if `codes` is
+// empty, only the grammar is checked.
+#[extendr]
+fn parse_element_triplets(x: Strings, codes: Strings, names: Strings) -> Robj {
+    let codes: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
+    let names: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+
+    let mut triplets: Vec<String> = Vec::with_capacity(x.len());
+
+    for s in x.iter() {
+        let triplet = match s.parse::<ElementTriplet>() {
+            Ok(t) => t,
+            Err(e) => return e.into(),
+        };
+
+        if !codes.is_empty() {
+            if let Err(e) = triplet.check_code(&codes, &names) {
+                return e.into();
+            }
+        }
+
+        triplets.push(triplet.to_string());
+    }
+
+    triplets.into_robj()
+}
+
+// BATCHES ---------------------------------------------------------------------
+// pack stations into requests for the data endpoint by the elements (and, for
+// hourly data, the values) each is expected to return, and split long hourly
+// and daily date ranges into windows if `split_dates`. `element_metadata` is
+// the `element_metadata` list column from the stations endpoint, or NULL to
+// fall back on `request_size` stations per request. under a cassette, today is
+// the date it was recorded on.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn batch_station_triplets(
+    station_triplets: Strings,
+    element_metadata: Robj,
+    elements: Strings,
+    duration: Robj,
+    begin_date: Robj,
+    end_date: Robj,
+    request_size: usize,
+    split_dates: bool,
+    cassette: Robj,
+) -> Robj {
+    let today = match Cassette::from_robj(&cassette) {
+        Ok(Some(cassette)) => cassette.today(),
+        _ => None,
+    }
+    .unwrap_or_else(dates::today);
+
+    let triplets: Vec<String> = station_triplets.iter().map(|t| t.to_string()).collect();
+
+    let metadata: Vec<Option<Robj>> = match element_metadata.as_list() {
+        Some(lst) => lst
+            .values()
+            .map(|x| if x.is_null() { None } else { Some(x) })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let query = batch::DataQuery {
+        elements: elements.iter().filter_map(|e| e.parse().ok()).collect(),
+        duration: duration.as_str(),
+        begin_date: batch::query_date(&begin_date),
+        end_date: batch::query_date(&end_date),
+    };
+
+    batch::batches_robj(&batch::batch_station_triplets(
+        &triplets,
+        &metadata,
+        &query,
+        request_size,
+        split_dates,
+        today,
+    ))
+}
+
+// UPDATES ---------------------------------------------------------------------
+// bring a previous `get_elements()` result up to date. `last_observed_dates()`
+// gives the last date each station element has a value, and
+// `update_station_dataset()` merges newly requested values into the old ones.
+#[extendr]
+fn last_observed_dates(x: Robj) -> Result<Robj> {
+    let sd = StationDataSet::from_robj(&x).map_err(Error::Other)?;
+
+    update::last_observed_robj(&sd).map_err(Error::Other)
+}
+
+#[extendr]
+fn update_station_dataset(
+    x: Robj,
+    new: Robj,
+    long: bool,
+    triplet_columns: bool,
+    hucs: Robj,
+) -> Result<Robj> {
+    let old = StationDataSet::from_robj(&x).map_err(Error::Other)?;
+    let new = StationDataSet::from_robj(&new).map_err(Error::Other)?;
+
+    match old.update(new) {
+        Ok(sd) => Ok(with_station_columns(
+            sd.into_robj_as(long),
+            triplet_columns,
+            StationHucs::from_robj(&hucs).as_ref(),
+        )),
+        Err(e) => Ok(e.into()),
+    }
+}
+
+// SHEF ------------------------------------------------------------------------
+// encode a `get_elements()` result, wide or long, as SHEF .A and .E messages.
+// `shef_ids` and `time_zones` are named by station triplet, `pe_codes` and
+// `functions` by element code.
+#[extendr]
+fn encode_station_dataset_shef(
+    x: Robj,
+    shef_ids: Robj,
+    time_zones: Robj,
+    pe_codes: Robj,
+    functions: Robj,
+) -> Result<Robj> {
+    let sd = StationDataSet::from_robj(&x).map_err(Error::Other)?;
+    let codes = ShefCodes::new(&shef_ids, &time_zones, &pe_codes, &functions);
+
+    Ok(sd.encode_shef(&codes))
+}
+
+// NORMALS ---------------------------------------------------------------------
+// daily normals of a `get_elements()` result, wide or long, over water years
+// `begin_year` through `end_year`. `percentiles` are percents, and `leap_day`
+// is "drop", "merge" or "keep" (see `LeapDay`).
+#[extendr]
+fn station_dataset_normals(
+    x: Robj,
+    begin_year: i32,
+    end_year: i32,
+    percentiles: &[f64],
+    leap_day: &str,
+) -> Result<Robj> {
+    let sd = StationDataSet::from_robj(&x).map_err(Error::Other)?;
+    let opts =
+        NormalsOptions::new(begin_year, end_year, percentiles, leap_day).map_err(Error::Other)?;
+
+    Ok(sd.normals(&opts))
+}
+
+// CACHE -----------------------------------------------------------------------
+// inspect and tidy up the response cache in `dir`. pruning removes expired
+// entries and clearing removes them all; both return how many were removed.
+#[extendr]
+fn cache_entries(dir: Robj) -> Robj {
+    match Cache::from_robj(&dir) {
+        Some(cache) => cache::entries_robj(&cache.entries()),
+        None => cache::entries_robj(&[]),
+    }
+}
+
+#[extendr]
+fn cache_remove(dir: Robj, expired_only: bool) -> i32 {
+    let Some(cache) = Cache::from_robj(&dir) else {
+        return 0;
+    };
+
+    cache
+        .entries()
+        .iter()
+        .filter(|e| !expired_only || e.is_expired())
+        .filter(|e| e.remove())
+        .count() as i32
+}
+
+// add the triplet and HUC columns asked for to a data or forecast table
+fn with_station_columns(df: Robj, triplet_columns: bool, hucs: Option<&StationHucs>) -> Robj {
+    let df = if triplet_columns {
+        with_triplet_columns(df)
+    } else {
+        df
+    };
+
+    match hucs {
+        Some(hucs) => huc::with_huc_columns(df, Some(hucs)),
+        None => df,
+    }
+}
+
+// add `station_id`, `state_code` and `network_code` columns right after the
+// `station_triplet` column of a data frame
+fn with_triplet_columns(df: Robj) -> Robj {
+    let Ok(lst) = List::try_from(&df) else {
+        return df;
+    };
+
+    let Some(i) = lst
+        .names()
+        .and_then(|mut n| n.position(|x| x == "station_triplet"))
+    else {
+        return df;
+    };
+
+    let triplets: Vec<Option<StationTriplet>> = lst
+        .elt(i)
+        .map(|x| strings(&x))
+        .unwrap_or_default()
+        .iter()
+        .map(|t| {
+            t.as_deref()
+                .and_then(|t| StationTriplet::from_response(t).ok())
+        })
         .collect();
 
-    StationForecastSet(vec_data).into()
+    let part = |f: fn(&StationTriplet) -> &str| -> Robj {
+        triplets
+            .iter()
+            .map(|t| t.as_ref().map(|t| f(t).to_string()))
+            .collect::<Vec<Option<String>>>()
+            .into_robj()
+    };
+
+    let columns = [
+        ("station_id", part(|t| &t.station_id)),
+        ("state_code", part(|t| &t.state_code)),
+        ("network_code", part(|t| &t.network_code)),
+    ];
+
+    splice_columns(df, &[], Some(i), columns)
 }
 
 // REFERENCES ------------------------------------------------------------------
@@ -398,49 +1352,69 @@ struct UnitRef {
 }
 
 #[extendr]
-fn parse_station_reference_json(x: Strings, reference_type: Strings) -> Robj {
-    let json = x[0].as_str();
-    let rtype = reference_type[0].as_str();
+fn fetch_station_reference(
+    base_url: &str,
+    reference_type: &str,
+    cache_dir: Robj,
+    cassette: Robj,
+) -> Robj {
+    let request = Request {
+        base_url,
+        endpoint: "reference-data",
+        query: vec![("referenceLists".to_string(), reference_type.to_string())],
+    };
 
+    let result = fetch_one(&request, &cache_dir, &cassette, |json| {
+        parse_reference(json, reference_type)
+    });
+
+    match result {
+        Ok(Ok(df)) => df,
+        Ok(Err(e)) => e.into(),
+        Err(e) => e.into(),
+    }
+}
+
+fn parse_reference(json: &str, rtype: &str) -> std::result::Result<Robj, ParseError> {
     let mut df = match rtype {
         "dcos" => {
-            let dco = serde_json::from_str::<DcoDto>(json).unwrap();
+            let dco: DcoDto = from_json(json, 1)?;
             dco.dcos.into_dataframe().into_robj()
         }
         "durations" => {
-            let duration = serde_json::from_str::<DurationDto>(json).unwrap();
+            let duration: DurationDto = from_json(json, 1)?;
             duration.durations.into_dataframe().into_robj()
         }
         "elements" => {
-            let element = serde_json::from_str::<ElementDto>(json).unwrap();
+            let element: ElementDto = from_json(json, 1)?;
             element.elements.into_dataframe().into_robj()
         }
         "forecastPeriods" => {
-            let forecast = serde_json::from_str::<ForecastDto>(json).unwrap();
+            let forecast: ForecastDto = from_json(json, 1)?;
             forecast.forecast_periods.into_dataframe().into_robj()
         }
         "functions" => {
-            let function = serde_json::from_str::<FunctionDto>(json).unwrap();
+            let function: FunctionDto = from_json(json, 1)?;
             function.functions.into_dataframe().into_robj()
         }
         "instruments" => {
-            let instrument = serde_json::from_str::<InstrumentDto>(json).unwrap();
+            let instrument: InstrumentDto = from_json(json, 1)?;
             instrument.instruments.into_dataframe().into_robj()
         }
         "networks" => {
-            let network = serde_json::from_str::<NetworkDto>(json).unwrap();
+            let network: NetworkDto = from_json(json, 1)?;
             network.networks.into_dataframe().into_robj()
         }
         "physicalElements" => {
-            let phys_el = serde_json::from_str::<PhysicalElementDto>(json).unwrap();
+            let phys_el: PhysicalElementDto = from_json(json, 1)?;
             phys_el.physical_elements.into_dataframe().into_robj()
         }
         "states" => {
-            let state = serde_json::from_str::<StateDto>(json).unwrap();
+            let state: StateDto = from_json(json, 1)?;
             state.states.into_dataframe().into_robj()
         }
         "units" => {
-            let unit = serde_json::from_str::<UnitDto>(json).unwrap();
+            let unit: UnitDto = from_json(json, 1)?;
             unit.units.into_dataframe().into_robj()
         }
         _ => data_frame!(),
@@ -448,7 +1422,7 @@ fn parse_station_reference_json(x: Strings, reference_type: Strings) -> Robj {
 
     df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
 
-    drop_empty_columns(&df).unwrap()
+    Ok(drop_empty_columns(&df).unwrap())
 }
 
 // STATION METADATA ------------------------------------------------------------
@@ -571,14 +1545,8 @@ impl From<StationMetadataSet> for Robj {
             end_date.push(x.end_date);
 
             if let Some(e) = x.station_elements {
-                let mut elements_df = e.into_dataframe().unwrap().into_robj();
-
-                elements_df
-                    .set_class(&["tbl_df", "tbl", "data.frame"])
-                    .unwrap();
-
                 station_elements
-                    .set_elt(i, drop_empty_columns(&elements_df).unwrap())
+                    .set_elt(i, station_elements_robj(e))
                     .unwrap();
             }
 
@@ -610,8 +1578,8 @@ impl From<StationMetadataSet> for Robj {
             data_time_zone = data_time_zone,
             pedon_code = pedon_code,
             shef_id = shef_id,
-            begin_date = begin_date,
-            end_date = end_date,
+            begin_date = dates::dates_robj(begin_date.iter().map(|d| d.as_deref())),
+            end_date = dates::dates_robj(end_date.iter().map(|d| d.as_deref())),
             forecast_metadata = station_forecasts,
             reservoir_metadata = station_reservoir,
             element_metadata = station_elements
@@ -623,14 +1591,442 @@ impl From<StationMetadataSet> for Robj {
     }
 }
 
+// like deriving IntoDataFrameRow, but with begin and end dates as Date
+fn station_elements_robj(x: Vec<StationElement>) -> Robj {
+    let mut df = data_frame!(
+        element_code = x
+            .iter()
+            .map(|e| e.element_code.as_str())
+            .collect::<Vec<_>>(),
+        ordinal = x.iter().map(|e| e.ordinal).collect::<Vec<_>>(),
+        height_depth = x.iter().map(|e| e.height_depth).collect::<Vec<_>>(),
+        duration_name = x
+            .iter()
+            .map(|e| e.duration_name.as_str())
+            .collect::<Vec<_>>(),
+        data_precision = x.iter().map(|e| e.data_precision).collect::<Vec<_>>(),
+        stored_unit_code = x
+            .iter()
+            .map(|e| e.stored_unit_code.as_str())
+            .collect::<Vec<_>>(),
+        original_unit_code = x
+            .iter()
+            .map(|e| e.original_unit_code.as_str())
+            .collect::<Vec<_>>(),
+        begin_date = dates::dates_robj(x.iter().map(|e| Some(e.begin_date.as_str()))),
+        end_date = dates::dates_robj(x.iter().map(|e| Some(e.end_date.as_str()))),
+        derived_data = x.iter().map(|e| e.derived_data).collect::<Vec<_>>()
+    );
+
+    df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+    drop_empty_columns(&df).unwrap()
+}
+
+// stations are kept if they're in the `aoi` and under one of the `hucs`, and
+// with `huc_columns` get a `huc2` through `huc12` column for each HUC level
+fn station_metadataset_robj(
+    results: Vec<Chunk<StationMetadataSet>>,
+    aoi: Option<Aoi>,
+    hucs: Option<HucFilter>,
+    huc_columns: bool,
+    geometry: Option<Geometry>,
+    output: Option<Output>,
+) -> Robj {
+    let mut sm = match collect_chunks(results, false, |_| String::new()) {
+        Ok((chunks, _)) => StationMetadataSet(chunks.into_iter().flat_map(|v| v.0).collect()),
+        Err(e) => return e.into(),
+    };
+
+    if let Some(aoi) = aoi {
+        sm.retain_in(&aoi);
+    }
+
+    if let Some(hucs) = hucs {
+        sm.retain_hucs(&hucs);
+    }
+
+    match (output, geometry) {
+        (Some(output), _) => output.write(&sm.record_batch(huc_columns)),
+        (None, Some(geometry)) => sm.into_sf(geometry, huc_columns),
+        (None, None) if huc_columns => huc::with_huc_columns(sm.into(), None),
+        (None, None) => sm.into(),
+    }
+}
+
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+fn fetch_station_metadataset(
+    base_url: &str,
+    query: Robj,
+    cache_dir: Robj,
+    cassette: Robj,
+    aoi: Robj,
+    buffer: f64,
+    hucs: Robj,
+    huc_columns: bool,
+    geometry: Robj,
+    output: Robj,
+) -> Robj {
+    // stations outside the area of interest are dropped right after parsing
+    let aoi = match Aoi::from_robj(&aoi, buffer) {
+        Ok(aoi) => aoi,
+        Err(e) => return e.into(),
+    };
+
+    let request = Request {
+        base_url,
+        endpoint: "stations",
+        query: named_strings(&query),
+    };
+
+    match fetch_one(&request, &cache_dir, &cassette, |json| from_json(json, 1)) {
+        Ok(result) => station_metadataset_robj(
+            vec![(0, result)],
+            aoi,
+            HucFilter::from_robj(&hucs),
+            huc_columns,
+            Geometry::from_robj(&geometry),
+            Output::from_robj(&output),
+        ),
+        Err(e) => e.into(),
+    }
+}
+
+// the `k` stations nearest a point, and/or those within `radius` km of it,
+// that pass the `filters` (see `StationFilter`). stations come back nearest
+// first as an sf table, with `distance` (km) and `bearing` (degrees) columns.
 #[extendr]
-fn parse_station_metadataset_json(x: Strings) -> Robj {
-    let vec_metadata = x
+#[allow(clippy::too_many_arguments)]
+fn fetch_nearest_stations(
+    base_url: &str,
+    query: Robj,
+    cache_dir: Robj,
+    cassette: Robj,
+    point: &[f64],
+    k: Option<i32>,
+    radius: Option<f64>,
+    filters: Robj,
+    huc_columns: bool,
+    geometry: Robj,
+) -> Robj {
+    let request = Request {
+        base_url,
+        endpoint: "stations",
+        query: named_strings(&query),
+    };
+
+    let result = match fetch_one(&request, &cache_dir, &cassette, |json| {
+        from_json::<StationMetadataSet>(json, 1)
+    }) {
+        Ok(result) => result,
+        Err(e) => return e.into(),
+    };
+
+    let sm = match collect_chunks(vec![(0, result)], false, |_| String::new()) {
+        Ok((chunks, _)) => StationMetadataSet(chunks.into_iter().flat_map(|v| v.0).collect()),
+        Err(e) => return e.into(),
+    };
+
+    let nearest = sm.nearest(
+        point[0],
+        point[1],
+        k.map(|k| k.max(0) as usize),
+        radius,
+        &StationFilter::from_robj(&filters),
+    );
+
+    let extra = vec![
+        ("distance", nearest.distance.into_robj()),
+        ("bearing", nearest.bearing.into_robj()),
+    ];
+
+    match Geometry::from_robj(&geometry) {
+        Some(geometry) => nearest.stations.into_sf_with(geometry, huc_columns, extra),
+        None if huc_columns => splice_columns(
+            huc::with_huc_columns(nearest.stations.into(), None),
+            &[],
+            None,
+            extra,
+        ),
+        None => splice_columns(nearest.stations.into(), &[], None, extra),
+    }
+}
+
+// a chunk of json by its zero-based position, and how parsing it went
+type Chunk<T> = (usize, std::result::Result<T, ParseError>);
+
+// split parsed chunks into those that parsed and the first error, so R can
+// report which chunk it was. in lenient mode, chunks that fail are instead
+// recorded in diagnostics, with `station_triplets(i)` naming the stations in
+// chunk i.
+fn collect_chunks<T, F>(
+    results: Vec<Chunk<T>>,
+    lenient: bool,
+    station_triplets: F,
+) -> std::result::Result<(Vec<T>, Option<ParseDiagnostics>), ParseError>
+where
+    F: Fn(usize) -> String,
+{
+    if !lenient {
+        let chunks = results
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect::<std::result::Result<Vec<T>, ParseError>>()?;
+
+        return Ok((chunks, None));
+    }
+
+    let mut chunks = Vec::with_capacity(results.len());
+    let mut diagnostics = ParseDiagnostics::default();
+
+    for (i, result) in results {
+        match result {
+            Ok(chunk) => chunks.push(chunk),
+            Err(e) => diagnostics.push(e, station_triplets(i)),
+        }
+    }
+
+    Ok((chunks, Some(diagnostics)))
+}
+
+// the request for some or all of the stations in the batch at position `i`,
+// with the shared query parameters and the batch's date window, if it has one
+fn batch_request<'a>(
+    base_url: &'a str,
+    endpoint: &'a str,
+    query: &'a [(String, String)],
+    batches: &'a [Batch],
+) -> impl Fn(usize, &str) -> Request<'a> + Sync + 'a {
+    move |i, stations| {
+        let batch = &batches[i];
+        let windowed = |key: &str| match key {
+            "beginDate" => batch.begin_date.is_some(),
+            "endDate" => batch.end_date.is_some(),
+            _ => false,
+        };
+
+        let mut q = vec![("stationTriplets".to_string(), stations.to_string())];
+        q.extend(query.iter().filter(|(k, _)| !windowed(k)).cloned());
+
+        if let Some(date) = &batch.begin_date {
+            q.push(("beginDate".to_string(), date.clone()));
+        }
+
+        if let Some(date) = &batch.end_date {
+            q.push(("endDate".to_string(), date.clone()));
+        }
+
+        Request {
+            base_url,
+            endpoint,
+            query: q,
+        }
+    }
+}
+
+// responses for each part of each batch, in order, with the stations each
+// part covers, and the stations whose requests failed
+struct FetchedBatches<T> {
+    results: Vec<Chunk<T>>,
+    stations: Vec<String>,
+    failures: RequestFailures,
+}
+
+// GET and parse a single request with the cache and cassette from R, saving
+// anything recorded, even if the request failed, so a recording keeps the
+// error responses it got
+fn fetch_one<T, P>(request: &Request, cache_dir: &Robj, cassette: &Robj, parse: P) -> Fetched<T>
+where
+    P: FnOnce(&str) -> std::result::Result<T, ParseError>,
+{
+    let client = Client::from_robj(cache_dir, cassette)?;
+    let result = fetch::fetch_parsed(request, &client, parse);
+    client.finish()?;
+
+    result
+}
+
+// the same for batches of stations, saving before any error is returned
+fn fetch_batches<'a, T, F>(
+    batches: &[Batch],
+    request: F,
+    cache_dir: &Robj,
+    cassette: &Robj,
+    threads: usize,
+) -> std::result::Result<FetchedBatches<T>, RequestError>
+where
+    T: DeserializeOwned + Send,
+    F: Fn(usize, &str) -> Request<'a> + Sync,
+{
+    let stations: Vec<String> = batches.iter().map(|b| b.stations.clone()).collect();
+
+    let client = Client::from_robj(cache_dir, cassette)?;
+    let fetched = fetch::fetch_batches(&stations, request, &client, threads);
+    client.finish()?;
+
+    split_failures(fetched?, batches)
+}
+
+// set aside the parts whose request failed, along with their batch's date
+// window, indexing the rest by position in `stations` so parse errors can
+// still name their stations. cassette errors aren't the server's doing, so
+// they fail the whole call instead.
+fn split_failures<T>(
+    fetched: Vec<Parts<T>>,
+    batches: &[Batch],
+) -> std::result::Result<FetchedBatches<T>, RequestError> {
+    let mut out = FetchedBatches {
+        results: Vec::with_capacity(fetched.len()),
+        stations: Vec::with_capacity(fetched.len()),
+        failures: RequestFailures::default(),
+    };
+
+    for (parts, batch) in fetched.into_iter().zip(batches) {
+        for (stations, x) in parts {
+            match x {
+                Ok(result) => {
+                    out.results.push((out.stations.len(), result));
+                    out.stations.push(stations);
+                }
+                Err(e) if e.is_cassette() => return Err(e),
+                Err(e) => out.failures.push(e, &stations, batch),
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+fn with_failures(mut df: Robj, failures: RequestFailures) -> Robj {
+    // conditions go back to R untouched
+    if df.inherits("condition") {
+        return df;
+    }
+
+    df.set_attrib("failed_requests", Robj::from(failures))
+        .unwrap();
+
+    df
+}
+
+fn with_diagnostics(mut df: Robj, diagnostics: ParseDiagnostics) -> Robj {
+    df.set_attrib("diagnostics", Robj::from(diagnostics))
+        .unwrap();
+
+    df
+}
+
+fn from_json<T: DeserializeOwned>(json: &str, chunk: usize) -> std::result::Result<T, ParseError> {
+    serde_json::from_str::<T>(json).map_err(|e| ParseError::new(chunk, json, &e))
+}
+
+// helper to read a named character vector from R into key-value pairs,
+// skipping NAs
+fn named_strings(x: &Robj) -> Vec<(String, String)> {
+    let (Some(names), Some(values)) = (x.names(), x.as_str_iter()) else {
+        return Vec::new();
+    };
+
+    names
+        .zip(values)
+        .filter(|(_, v)| !v.is_na())
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+// helper to read a named numeric vector from R into a lookup, skipping NAs
+fn named_doubles(x: &Robj) -> HashMap<String, f64> {
+    let (Some(names), Some(values)) = (x.names(), x.as_real_slice()) else {
+        return HashMap::new();
+    };
+
+    names
+        .zip(values.iter())
+        .filter(|(_, v)| !v.is_na())
+        .map(|(k, v)| (k.to_string(), *v))
+        .collect()
+}
+
+// helpers to read a vector from R, with NAs as None. a vector of another type,
+// e.g. a column of all NA that R made logical, reads as all NA.
+fn strings(x: &Robj) -> Vec<Option<String>> {
+    match x.as_str_iter() {
+        Some(it) => it.map(|s| (!s.is_na()).then(|| s.to_string())).collect(),
+        None => vec![None; x.len()],
+    }
+}
+
+fn integers(x: &Robj) -> Vec<Option<i32>> {
+    match x.as_integer_slice() {
+        Some(x) => x.iter().map(|v| (!v.is_na()).then_some(*v)).collect(),
+        None => vec![None; x.len()],
+    }
+}
+
+// integers are read as doubles too
+fn doubles(x: &Robj) -> Vec<Option<f64>> {
+    if let Some(x) = x.as_real_slice() {
+        x.iter().map(|v| (!v.is_nan()).then_some(*v)).collect()
+    } else if let Some(x) = x.as_integer_slice() {
+        x.iter()
+            .map(|v| (!v.is_na()).then_some(*v as f64))
+            .collect()
+    } else {
+        vec![None; x.len()]
+    }
+}
+
+// helper to rebuild a data frame without its `drop` columns, and with
+// `columns` inserted after the column at position `after`, or at the end. row
+// names are kept, and the result is a tibble.
+fn splice_columns<I, S>(df: Robj, drop: &[&str], after: Option<usize>, columns: I) -> Robj
+where
+    I: IntoIterator<Item = (S, Robj)>,
+    S: Into<String>,
+{
+    let Ok(lst) = List::try_from(&df) else {
+        return df;
+    };
+
+    let mut columns: Vec<(String, Robj)> = columns
         .into_iter()
-        .flat_map(|v| serde_json::from_str::<StationMetadataSet>(v).unwrap().0)
+        .map(|(name, value)| (name.into(), value))
         .collect();
 
-    StationMetadataSet(vec_metadata).into()
+    let mut names: Vec<String> = Vec::with_capacity(lst.len() + columns.len());
+    let mut values: Vec<Robj> = Vec::with_capacity(lst.len() + columns.len());
+
+    for (j, (name, value)) in lst.iter().enumerate() {
+        if !drop.contains(&name) {
+            names.push(name.to_string());
+            values.push(value);
+        }
+
+        if after == Some(j) {
+            columns.drain(..).for_each(|(name, value)| {
+                names.push(name);
+                values.push(value);
+            });
+        }
+    }
+
+    for (name, value) in columns {
+        names.push(name);
+        values.push(value);
+    }
+
+    let mut out = List::from_names_and_values(names, values)
+        .unwrap()
+        .into_robj();
+
+    if let Some(row_names) = df.get_attrib("row.names") {
+        out.set_attrib("row.names", row_names).unwrap();
+    }
+
+    out.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+    out
 }
 
 // helper to clean tables when they have empty columns
@@ -649,7 +2045,7 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
         let col = lst.index(col_name)?;
         match &col.rtype() {
             Rtype::Logicals => {
-                for xi in Logicals::try_from(col)?.into_iter() {
+                for xi in Logicals::try_from(col)?.iter() {
                     if !xi.is_na() {
                         to_keep.push(col_name);
                         break;
@@ -657,7 +2053,7 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
                 }
             }
             Rtype::Integers => {
-                for xi in Integers::try_from(col)?.into_iter() {
+                for xi in Integers::try_from(col)?.iter() {
                     if !xi.is_na() {
                         to_keep.push(col_name);
                         break;
@@ -665,7 +2061,7 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
                 }
             }
             Rtype::Doubles => {
-                for xi in Doubles::try_from(col)?.into_iter() {
+                for xi in Doubles::try_from(col)?.iter() {
                     if !xi.is_na() {
                         to_keep.push(col_name);
                         break;
@@ -673,7 +2069,7 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
                 }
             }
             Rtype::Strings => {
-                for xi in Strings::try_from(col)?.into_iter() {
+                for xi in Strings::try_from(col)?.iter() {
                     if !xi.is_na() {
                         to_keep.push(col_name);
                         break;
@@ -691,7 +2087,7 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
             _ => (),
         }
     }
-    Ok(x.slice(to_keep)?)
+    x.slice(to_keep)
 }
 
 // TODO: parse json from references endpoint
@@ -701,8 +2097,18 @@ fn drop_empty_columns(x: &Robj) -> Result<Robj> {
 // See corresponding C code in `entrypoint.c`.
 extendr_module! {
     mod awdb;
-    fn parse_station_dataset_json;
-    fn parse_station_forecast_set_json;
-    fn parse_station_reference_json;
-    fn parse_station_metadataset_json;
+    fn fetch_station_dataset;
+    fn fetch_station_forecast_set;
+    fn fetch_station_reference;
+    fn fetch_station_metadataset;
+    fn fetch_nearest_stations;
+    fn build_station_triplets;
+    fn parse_element_triplets;
+    fn batch_station_triplets;
+    fn last_observed_dates;
+    fn update_station_dataset;
+    fn encode_station_dataset_shef;
+    fn station_dataset_normals;
+    fn cache_entries;
+    fn cache_remove;
 }
@@ -0,0 +1,364 @@
+use extendr_api::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::dates;
+use crate::element::ElementTriplet;
+use crate::huc::HucFilter;
+use crate::{StationMetadata, StationMetadataSet, EARTH_RADIUS};
+
+// NEAREST STATIONS ------------------------------------------------------------
+// the stations closest to a point, by great-circle distance. stations are
+// indexed in a kd-tree over their position on the unit sphere, where the
+// straight-line (chord) distance between two points grows with the
+// great-circle distance between them, so the tree finds exactly the same
+// neighbors a search on the sphere would. stations that don't pass the filters
+// on network, HUC, element, active period and elevation are skipped as the tree
+// is searched, so asking for k stations gets k that pass.
+
+fn unit_vector(longitude: f64, latitude: f64) -> [f64; 3] {
+    let (lon, lat) = (longitude.to_radians(), latitude.to_radians());
+
+    [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()]
+}
+
+fn squared_chord(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    (0..3).map(|i| (a[i] - b[i]).powi(2)).sum()
+}
+
+// great-circle distance in km, from the chord between two unit vectors
+fn chord_to_km(squared_chord: f64) -> f64 {
+    2.0 * EARTH_RADIUS * (squared_chord.sqrt() / 2.0).min(1.0).asin()
+}
+
+fn km_to_squared_chord(km: f64) -> f64 {
+    let angle = (km / EARTH_RADIUS).min(std::f64::consts::PI);
+
+    (2.0 * (angle / 2.0).sin()).powi(2)
+}
+
+// initial bearing from the first point to the second, in degrees clockwise
+// from north
+fn bearing(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lon1, lat1) = (from.0.to_radians(), from.1.to_radians());
+    let (lon2, lat2) = (to.0.to_radians(), to.1.to_radians());
+    let dlon = lon2 - lon1;
+
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+// KD-TREE ---------------------------------------------------------------------
+// a balanced tree held implicitly in one array of station indices: the median
+// of each slice along the splitting axis sits in its middle, with the lower
+// half to its left and the upper half to its right
+struct KdTree {
+    points: Vec<[f64; 3]>,
+    order: Vec<usize>,
+}
+
+// a candidate neighbor, ordered by distance so the heap keeps the farthest on
+// top
+#[derive(Debug, Clone, Copy)]
+struct Candidate {
+    distance: f64,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+impl KdTree {
+    fn new(points: Vec<[f64; 3]>) -> Self {
+        let mut order: Vec<usize> = (0..points.len()).collect();
+
+        build(&points, &mut order, 0);
+
+        KdTree { points, order }
+    }
+
+    // up to `k` points within `max` squared chord of the target that pass
+    // `keep`, nearest first
+    fn search<F>(&self, target: &[f64; 3], k: usize, max: f64, keep: F) -> Vec<Candidate>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let mut heap = BinaryHeap::new();
+
+        if k > 0 {
+            self.visit(&self.order, 0, target, k, max, &keep, &mut heap);
+        }
+
+        heap.into_sorted_vec()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn visit<F>(
+        &self,
+        slice: &[usize],
+        depth: usize,
+        target: &[f64; 3],
+        k: usize,
+        max: f64,
+        keep: &F,
+        heap: &mut BinaryHeap<Candidate>,
+    ) where
+        F: Fn(usize) -> bool,
+    {
+        if slice.is_empty() {
+            return;
+        }
+
+        let mid = slice.len() / 2;
+        let index = slice[mid];
+        let point = &self.points[index];
+        let axis = depth % 3;
+
+        let distance = squared_chord(point, target);
+
+        if distance <= max && keep(index) {
+            heap.push(Candidate { distance, index });
+
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+
+        let diff = target[axis] - point[axis];
+        let (near, far) = if diff < 0.0 {
+            (&slice[..mid], &slice[mid + 1..])
+        } else {
+            (&slice[mid + 1..], &slice[..mid])
+        };
+
+        self.visit(near, depth + 1, target, k, max, keep, heap);
+
+        // the far side can only hold something closer if the splitting plane
+        // is closer than the worst candidate so far
+        let bound = if heap.len() < k {
+            max
+        } else {
+            heap.peek().map_or(max, |c| c.distance)
+        };
+
+        if diff * diff <= bound {
+            self.visit(far, depth + 1, target, k, max, keep, heap);
+        }
+    }
+}
+
+fn build(points: &[[f64; 3]], slice: &mut [usize], depth: usize) {
+    if slice.len() <= 1 {
+        return;
+    }
+
+    let axis = depth % 3;
+    let mid = slice.len() / 2;
+
+    slice.select_nth_unstable_by(mid, |&a, &b| points[a][axis].total_cmp(&points[b][axis]));
+
+    let (lower, upper) = slice.split_at_mut(mid);
+
+    build(points, lower, depth + 1);
+    build(points, &mut upper[1..], depth + 1);
+}
+
+// FILTERS ---------------------------------------------------------------------
+// from R, a list with any of
+// - `networks`, network codes
+// - `hucs`, HUC codes that a station's HUC must start with
+// - `elements`, element triplets, at least one of which a station must have
+// - `active`, a begin and end date that a station's period of record (and
+//   that of its matching elements) must cover
+// - `elevation`, a lowest and highest elevation in feet, either of which can
+//   be NA
+#[derive(Debug, Default)]
+pub(crate) struct StationFilter {
+    networks: Option<HashSet<String>>,
+    hucs: Option<HucFilter>,
+    elements: Vec<ElementTriplet>,
+    active: Option<(f64, f64)>,
+    elevation: (Option<f64>, Option<f64>),
+}
+
+impl StationFilter {
+    pub(crate) fn from_robj(x: &Robj) -> Self {
+        let Ok(lst) = List::try_from(x) else {
+            return StationFilter::default();
+        };
+
+        let mut filter = StationFilter::default();
+
+        // the strings of a character vector, leaving out NAs
+        let strings = |x: &Robj| crate::strings(x).into_iter().flatten();
+
+        for (name, value) in lst.iter() {
+            match name {
+                "networks" => {
+                    let networks: HashSet<String> = strings(&value).filter(|n| n != "*").collect();
+
+                    if !networks.is_empty() {
+                        filter.networks = Some(networks);
+                    }
+                }
+                "hucs" => filter.hucs = HucFilter::from_robj(&value),
+                "elements" => {
+                    filter.elements = strings(&value)
+                        .filter(|e| e != "*")
+                        .filter_map(|e| e.parse().ok())
+                        .collect();
+                }
+                "active" => {
+                    let dates: Vec<Option<f64>> =
+                        strings(&value).map(|d| dates::parse_date(&d)).collect();
+
+                    if let [Some(begin), Some(end)] = dates[..] {
+                        filter.active = Some((begin, end));
+                    }
+                }
+                "elevation" => {
+                    if let [lo, hi] = crate::doubles(&value)[..] {
+                        filter.elevation = (lo, hi);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        filter
+    }
+
+    // whether a period of record, as AWDB's date strings, covers the active
+    // period. a missing end date is taken to mean still active.
+    fn covers(&self, begin: Option<&str>, end: Option<&str>) -> bool {
+        let Some((from, to)) = self.active else {
+            return true;
+        };
+
+        let begin = begin.and_then(dates::parse_date);
+        let end = end.and_then(dates::parse_date);
+
+        begin.map_or(false, |b| b <= from) && end.map_or(true, |e| e >= to)
+    }
+
+    fn keep(&self, station: &StationMetadata) -> bool {
+        if let Some(networks) = &self.networks {
+            if !networks.contains(&station.network_code) {
+                return false;
+            }
+        }
+
+        if let Some(hucs) = &self.hucs {
+            if !hucs.keep(station) {
+                return false;
+            }
+        }
+
+        let (lo, hi) = self.elevation;
+
+        if lo.is_some() || hi.is_some() {
+            let Some(elevation) = station.elevation else {
+                return false;
+            };
+
+            if lo.map_or(false, |lo| elevation < lo) || hi.map_or(false, |hi| elevation > hi) {
+                return false;
+            }
+        }
+
+        if !self.covers(station.begin_date.as_deref(), station.end_date.as_deref()) {
+            return false;
+        }
+
+        if self.elements.is_empty() {
+            return true;
+        }
+
+        let Some(station_elements) = &station.station_elements else {
+            return false;
+        };
+
+        station_elements.iter().any(|se| {
+            self.elements
+                .iter()
+                .any(|t| t.matches(&se.element_code, se.height_depth, Some(se.ordinal)))
+                && self.covers(Some(&se.begin_date), Some(&se.end_date))
+        })
+    }
+}
+
+// SEARCH ----------------------------------------------------------------------
+pub(crate) struct Nearest {
+    pub(crate) stations: StationMetadataSet,
+    // km
+    pub(crate) distance: Vec<f64>,
+    // degrees clockwise from north
+    pub(crate) bearing: Vec<f64>,
+}
+
+impl StationMetadataSet {
+    // the `k` stations nearest the point (all of them if None) within
+    // `radius` km (anywhere if None) that pass the filter, nearest first
+    pub(crate) fn nearest(
+        self,
+        longitude: f64,
+        latitude: f64,
+        k: Option<usize>,
+        radius: Option<f64>,
+        filter: &StationFilter,
+    ) -> Nearest {
+        let tree = KdTree::new(
+            self.0
+                .iter()
+                .map(|s| unit_vector(s.longitude, s.latitude))
+                .collect(),
+        );
+
+        let target = unit_vector(longitude, latitude);
+        let max = radius.map_or(f64::INFINITY, km_to_squared_chord);
+        let k = k.unwrap_or(self.0.len());
+
+        let found = tree.search(&target, k, max, |i| filter.keep(&self.0[i]));
+
+        let mut stations: Vec<Option<StationMetadata>> = self.0.into_iter().map(Some).collect();
+        let mut nearest = Nearest {
+            stations: StationMetadataSet(Vec::with_capacity(found.len())),
+            distance: Vec::with_capacity(found.len()),
+            bearing: Vec::with_capacity(found.len()),
+        };
+
+        for c in found.into_iter() {
+            let station = stations[c.index].take().unwrap();
+
+            nearest.distance.push(chord_to_km(c.distance));
+            nearest.bearing.push(bearing(
+                (longitude, latitude),
+                (station.longitude, station.latitude),
+            ));
+            nearest.stations.0.push(station);
+        }
+
+        nearest
+    }
+}
@@ -0,0 +1,240 @@
+use extendr_api::prelude::*;
+
+use crate::dates;
+use crate::StationDataSet;
+
+// NORMALS ---------------------------------------------------------------------
+// daily climatology for each station element, computed from its own history
+// over a chosen run of water years (e.g. 1991-2020) rather than the fixed
+// normal period the API reports `average` and `median` for. values are pooled
+// by day of the water year, from Oct 1 (day 1) to Sep 30, and summarized by
+// their count, mean, median and any percentiles asked for. percentiles are
+// interpolated between order statistics, as R's default `quantile(type = 7)`.
+// only daily values are used.
+
+// the months of the water year, in order
+const MONTHS: [u32; 12] = [10, 11, 12, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+// Feb 29 is day 152 of a 366 day water year
+const LEAP_DAY: usize = 152;
+
+// what to do with Feb 29, which only leap years have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LeapDay {
+    // leave Feb 29 values out, for a 365 day year
+    Drop,
+    // pool Feb 29 values with Feb 28, for a 365 day year
+    Merge,
+    // give Feb 29 its own day, for a 366 day year where Mar 1 is always 153
+    Keep,
+}
+
+impl LeapDay {
+    fn from_str(x: &str) -> Option<Self> {
+        match x {
+            "drop" => Some(LeapDay::Drop),
+            "merge" => Some(LeapDay::Merge),
+            "keep" => Some(LeapDay::Keep),
+            _ => None,
+        }
+    }
+
+    fn days(&self) -> usize {
+        match self {
+            LeapDay::Keep => 366,
+            _ => 365,
+        }
+    }
+
+    // the day of the water year a month-day falls on, or None if it's
+    // dropped
+    fn day_of_water_year(&self, month: u32, day: u32) -> Option<usize> {
+        // on the 366 day calendar, where every year has a Feb 29
+        let day = MONTHS
+            .iter()
+            .take_while(|&&m| m != month)
+            .map(|&m| dates::days_in_month(2000, m) as usize)
+            .sum::<usize>()
+            + day as usize;
+
+        match self {
+            LeapDay::Keep => Some(day),
+            LeapDay::Drop if day == LEAP_DAY => None,
+            LeapDay::Merge if day == LEAP_DAY => Some(LEAP_DAY - 1),
+            _ if day > LEAP_DAY => Some(day - 1),
+            _ => Some(day),
+        }
+    }
+
+    // the month and day for each day of the water year
+    fn month_days(&self) -> Vec<(u32, u32)> {
+        MONTHS
+            .iter()
+            .flat_map(|&m| (1..=dates::days_in_month(2000, m)).map(move |d| (m, d)))
+            .filter(|&(m, d)| *self == LeapDay::Keep || (m, d) != (2, 29))
+            .collect()
+    }
+}
+
+// the period and summaries asked for, passed from R
+pub(crate) struct NormalsOptions {
+    // first and last water year, inclusive
+    begin: i32,
+    end: i32,
+    // as percents, e.g. 10 and 90
+    percentiles: Vec<f64>,
+    leap_day: LeapDay,
+}
+
+impl NormalsOptions {
+    pub(crate) fn new(
+        begin: i32,
+        end: i32,
+        percentiles: &[f64],
+        leap_day: &str,
+    ) -> std::result::Result<Self, String> {
+        let leap_day = LeapDay::from_str(leap_day)
+            .ok_or_else(|| format!("unknown leap day policy `{leap_day}`"))?;
+
+        if percentiles.iter().any(|p| !(0.0..=100.0).contains(p)) {
+            return Err("percentiles must be between 0 and 100".to_string());
+        }
+
+        Ok(NormalsOptions {
+            begin,
+            end,
+            percentiles: percentiles.to_vec(),
+            leap_day,
+        })
+    }
+}
+
+// the `p` quantile of sorted values, interpolating between the two nearest
+fn quantile(sorted: &[f64], p: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let h = (sorted.len() - 1) as f64 * p;
+    let lo = h.floor() as usize;
+    let hi = h.ceil() as usize;
+
+    Some(sorted[lo] + (h - lo as f64) * (sorted[hi] - sorted[lo]))
+}
+
+// one row per station element per day of the water year
+#[derive(Default)]
+struct Normals {
+    station_triplet: Vec<String>,
+    element_code: Vec<String>,
+    ordinal: Vec<i32>,
+    height_depth: Vec<Option<i32>>,
+    stored_unit_code: Vec<String>,
+    day_of_water_year: Vec<i32>,
+    month: Vec<i32>,
+    day: Vec<i32>,
+    count: Vec<i32>,
+    mean: Vec<Option<f64>>,
+    median: Vec<Option<f64>>,
+    percentiles: Vec<Vec<Option<f64>>>,
+}
+
+impl StationDataSet {
+    // the values of station element i falling in the period, pooled by day of
+    // the water year
+    fn pool_by_day(&self, i: usize, opts: &NormalsOptions) -> Vec<Vec<f64>> {
+        let mut days: Vec<Vec<f64>> = vec![Vec::new(); opts.leap_day.days()];
+
+        for j in self.offsets[i]..self.offsets[i + 1] {
+            let (Some(date), Some(value)) = (self.values.date[j], self.values.value[j]) else {
+                continue;
+            };
+
+            let (year, month, day) =
+                dates::civil_from_days((date / dates::SECONDS_PER_DAY).floor() as i64);
+            let water_year = if month >= 10 { year + 1 } else { year };
+
+            if water_year < opts.begin || water_year > opts.end {
+                continue;
+            }
+
+            if let Some(d) = opts.leap_day.day_of_water_year(month, day) {
+                days[d - 1].push(value);
+            }
+        }
+
+        days
+    }
+
+    pub(crate) fn normals(&self, opts: &NormalsOptions) -> Robj {
+        let month_days = opts.leap_day.month_days();
+        let probabilities: Vec<f64> = opts.percentiles.iter().map(|p| p / 100.0).collect();
+
+        let mut out = Normals {
+            percentiles: vec![Vec::new(); probabilities.len()],
+            ..Normals::default()
+        };
+
+        for (i, se) in self.station_element.iter().enumerate() {
+            if se.duration_name != "DAILY" {
+                continue;
+            }
+
+            let days = self.pool_by_day(i, opts);
+
+            // nothing observed in the period
+            if days.iter().all(|d| d.is_empty()) {
+                continue;
+            }
+
+            let triplet = self.station_triplet[i].to_string();
+
+            for (d, mut values) in days.into_iter().enumerate() {
+                values.sort_by(|a, b| a.total_cmp(b));
+
+                let n = values.len();
+                let (month, day) = month_days[d];
+
+                out.station_triplet.push(triplet.clone());
+                out.element_code.push(se.element_code.clone());
+                out.ordinal.push(se.ordinal);
+                out.height_depth.push(se.height_depth);
+                out.stored_unit_code.push(se.stored_unit_code.clone());
+                out.day_of_water_year.push(d as i32 + 1);
+                out.month.push(month as i32);
+                out.day.push(day as i32);
+                out.count.push(n as i32);
+                out.mean
+                    .push((n > 0).then(|| values.iter().sum::<f64>() / n as f64));
+                out.median.push(quantile(&values, 0.5));
+
+                for (k, p) in probabilities.iter().enumerate() {
+                    out.percentiles[k].push(quantile(&values, *p));
+                }
+            }
+        }
+
+        let df = data_frame!(
+            station_triplet = out.station_triplet,
+            element_code = out.element_code,
+            ordinal = out.ordinal,
+            height_depth = out.height_depth,
+            stored_unit_code = out.stored_unit_code,
+            day_of_water_year = out.day_of_water_year,
+            month = out.month,
+            day = out.day,
+            count = out.count,
+            mean = out.mean,
+            median = out.median
+        );
+
+        // a `p10` column for the 10th percentile, `p2.5` for the 2.5th, etc.
+        let percentiles = opts
+            .percentiles
+            .iter()
+            .zip(out.percentiles)
+            .map(|(p, values)| (format!("p{p}"), values.into_robj()));
+
+        crate::splice_columns(df, &[], None, percentiles)
+    }
+}
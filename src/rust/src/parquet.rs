@@ -0,0 +1,506 @@
+use crate::arrow::{dictionary, Column, Field, RecordBatch};
+use crate::ipc;
+
+// PARQUET ---------------------------------------------------------------------
+// the Parquet file format, see https://parquet.apache.org/docs/file-format/
+//
+// a file is the magic "PAR1", then each row group's column chunks, then the
+// file metadata and its length, and "PAR1" again. every column chunk is a
+// single uncompressed v1 data page, plus a dictionary page for dictionary
+// encoded columns. page headers and file metadata are thrift structs, in the
+// compact protocol, written by the small writer below.
+//
+// Parquet has no timestamps in seconds, so those are stored in milliseconds.
+// the Arrow schema is kept in the file metadata under "ARROW:schema", the way
+// Arrow itself writes Parquet, so Arrow readers get back the original types.
+
+const MAGIC: &[u8] = b"PAR1";
+pub(crate) const ROW_GROUP_SIZE: usize = 1_000_000;
+
+// physical types
+const BOOLEAN: i32 = 0;
+const INT32: i32 = 1;
+const INT64: i32 = 2;
+const DOUBLE: i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+
+// encodings
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const RLE_DICTIONARY: i32 = 8;
+
+// page types
+const DATA_PAGE: i32 = 0;
+const DICTIONARY_PAGE: i32 = 2;
+
+// at most `row_group_size` rows go in each row group
+pub(crate) fn write(batch: &RecordBatch, row_group_size: usize) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    let n_row = batch.num_rows();
+    let row_group_size = row_group_size.max(1);
+
+    let mut row_groups = Vec::new();
+
+    for start in (0..n_row).step_by(row_group_size) {
+        let range = start..(start + row_group_size).min(n_row);
+        let chunks: Vec<ColumnChunk> = batch
+            .fields
+            .iter()
+            .map(|f| write_chunk(&mut out, f, &f.column.slice(range.clone())))
+            .collect();
+
+        row_groups.push((chunks, range.len()));
+    }
+
+    let mut t = Thrift::default();
+    file_metadata(&mut t, batch, &row_groups);
+
+    out.extend_from_slice(&t.buf);
+    out.extend_from_slice(&(t.buf.len() as u32).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+
+    out
+}
+
+// COLUMN CHUNKS ---------------------------------------------------------------
+// where a column chunk was written, for the file metadata
+struct ColumnChunk {
+    name: String,
+    physical_type: i32,
+    dictionary: bool,
+    num_values: usize,
+    dictionary_page_offset: Option<usize>,
+    data_page_offset: usize,
+    size: usize,
+}
+
+fn write_chunk(out: &mut Vec<u8>, field: &Field, column: &Column) -> ColumnChunk {
+    let start = out.len();
+    let n = column.len();
+
+    let mut data = Vec::new();
+
+    // definition levels, one bit each, prefixed by their length in bytes
+    if field.nullable {
+        let levels = rle((0..n).map(|i| column.is_valid(i) as u32), 1);
+
+        data.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+        data.extend_from_slice(&levels);
+    }
+
+    let mut dictionary_page_offset = None;
+
+    let encoding = match column {
+        Column::Dictionary(x) => {
+            let (indices, values) = dictionary(x);
+
+            let mut page = Vec::new();
+
+            for v in values.iter() {
+                byte_array(&mut page, v);
+            }
+
+            dictionary_page_offset = Some(out.len());
+            write_page(out, DICTIONARY_PAGE, PLAIN, &page, values.len());
+
+            // indices are as wide as the largest needs, with at least one bit
+            let bit_width = (32 - (values.len().saturating_sub(1) as u32).leading_zeros()).max(1);
+
+            data.push(bit_width as u8);
+            data.extend(rle(indices.iter().flatten().map(|&i| i as u32), bit_width));
+
+            RLE_DICTIONARY
+        }
+        Column::Utf8(x) => {
+            for v in x.iter().flatten() {
+                byte_array(&mut data, v);
+            }
+
+            PLAIN
+        }
+        Column::Int32(x) | Column::Date(x) => {
+            data.extend(x.iter().flatten().flat_map(|v| v.to_le_bytes()));
+            PLAIN
+        }
+        Column::Float64(x) => {
+            data.extend(x.iter().flatten().flat_map(|v| v.to_le_bytes()));
+            PLAIN
+        }
+        Column::Timestamp(x) => {
+            data.extend(x.iter().flatten().flat_map(|v| (v * 1000).to_le_bytes()));
+            PLAIN
+        }
+        Column::Boolean(x) => {
+            data.extend(ipc::bitmap(x.iter().flatten().copied()));
+            PLAIN
+        }
+    };
+
+    let data_page_offset = out.len();
+    write_page(out, DATA_PAGE, encoding, &data, n);
+
+    ColumnChunk {
+        name: field.name.clone(),
+        physical_type: physical_type(column),
+        dictionary: encoding == RLE_DICTIONARY,
+        num_values: n,
+        dictionary_page_offset,
+        data_page_offset,
+        size: out.len() - start,
+    }
+}
+
+fn physical_type(column: &Column) -> i32 {
+    match column {
+        Column::Utf8(_) | Column::Dictionary(_) => BYTE_ARRAY,
+        Column::Int32(_) | Column::Date(_) => INT32,
+        Column::Float64(_) => DOUBLE,
+        Column::Boolean(_) => BOOLEAN,
+        Column::Timestamp(_) => INT64,
+    }
+}
+
+fn byte_array(out: &mut Vec<u8>, x: &str) {
+    out.extend_from_slice(&(x.len() as u32).to_le_bytes());
+    out.extend_from_slice(x.as_bytes());
+}
+
+// a page header, then the page
+fn write_page(out: &mut Vec<u8>, page_type: i32, encoding: i32, page: &[u8], num_values: usize) {
+    let mut t = Thrift::default();
+
+    t.i32(1, page_type);
+    t.i32(2, page.len() as i32);
+    t.i32(3, page.len() as i32);
+
+    if page_type == DICTIONARY_PAGE {
+        t.begin_struct(7);
+        t.i32(1, num_values as i32);
+        t.i32(2, encoding);
+        t.end_struct();
+    } else {
+        t.begin_struct(5);
+        t.i32(1, num_values as i32);
+        t.i32(2, encoding);
+        t.i32(3, RLE);
+        t.i32(4, RLE);
+        t.end_struct();
+    }
+
+    t.stop();
+
+    out.extend_from_slice(&t.buf);
+    out.extend_from_slice(page);
+}
+
+// RLE / BIT-PACKED HYBRID -----------------------------------------------------
+// only the run length half is used, which suits both definition levels and
+// dictionary indices since they tend to come in long runs. each run is its
+// length shifted left one bit, then the value in as few bytes as fit
+// `bit_width`.
+fn rle<I: Iterator<Item = u32>>(x: I, bit_width: u32) -> Vec<u8> {
+    let width = (bit_width as usize + 7) / 8;
+    let mut out = Vec::new();
+    let mut run: Option<(u32, u64)> = None;
+
+    let flush = |out: &mut Vec<u8>, (value, count): (u32, u64)| {
+        varint(out, count << 1);
+        out.extend_from_slice(&value.to_le_bytes()[..width]);
+    };
+
+    for v in x {
+        run = match run {
+            Some((value, count)) if value == v => Some((value, count + 1)),
+            Some(r) => {
+                flush(&mut out, r);
+                Some((v, 1))
+            }
+            None => Some((v, 1)),
+        };
+    }
+
+    if let Some(r) = run {
+        flush(&mut out, r);
+    }
+
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+
+    out.push(x as u8);
+}
+
+// FILE METADATA ---------------------------------------------------------------
+fn file_metadata(t: &mut Thrift, batch: &RecordBatch, row_groups: &[(Vec<ColumnChunk>, usize)]) {
+    t.i32(1, 2);
+
+    // the schema is flattened depth first, with a root holding every column
+    t.begin_list(2, STRUCT, batch.fields.len() + 1);
+    t.begin_element();
+    t.binary(4, b"schema");
+    t.i32(5, batch.fields.len() as i32);
+    t.end_struct();
+
+    for f in batch.fields.iter() {
+        t.begin_element();
+        schema_element(t, f);
+        t.end_struct();
+    }
+
+    t.i64(3, batch.num_rows() as i64);
+
+    t.begin_list(4, STRUCT, row_groups.len());
+
+    for (i, (chunks, n_row)) in row_groups.iter().enumerate() {
+        t.begin_element();
+        row_group(t, chunks, *n_row, i);
+        t.end_struct();
+    }
+
+    t.begin_list(5, STRUCT, 1);
+    t.begin_element();
+    t.binary(1, b"ARROW:schema");
+    t.binary(2, base64(&ipc::schema_message(batch)).as_bytes());
+    t.end_struct();
+
+    t.binary(
+        6,
+        concat!("awdb version ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    t.stop();
+}
+
+// ConvertedType and LogicalType are both written, for older readers
+fn schema_element(t: &mut Thrift, f: &Field) {
+    t.i32(1, physical_type(&f.column));
+    t.i32(3, if f.nullable { 1 } else { 0 });
+    t.binary(4, f.name.as_bytes());
+
+    match f.column {
+        Column::Utf8(_) | Column::Dictionary(_) => {
+            t.i32(6, 0);
+            t.begin_struct(10);
+            t.empty_struct(1);
+            t.end_struct();
+        }
+        Column::Date(_) => {
+            t.i32(6, 6);
+            t.begin_struct(10);
+            t.empty_struct(6);
+            t.end_struct();
+        }
+        Column::Timestamp(_) => {
+            t.i32(6, 9);
+            t.begin_struct(10);
+            t.begin_struct(8);
+            t.bool(1, true);
+            t.begin_struct(2);
+            t.empty_struct(1);
+            t.end_struct();
+            t.end_struct();
+            t.end_struct();
+        }
+        _ => (),
+    }
+}
+
+fn row_group(t: &mut Thrift, chunks: &[ColumnChunk], n_row: usize, ordinal: usize) {
+    let size: usize = chunks.iter().map(|c| c.size).sum();
+    let offset = chunks.first().map_or(MAGIC.len(), chunk_offset);
+
+    t.begin_list(1, STRUCT, chunks.len());
+
+    for c in chunks.iter() {
+        t.begin_element();
+        t.i64(2, chunk_offset(c) as i64);
+        t.begin_struct(3);
+        column_metadata(t, c);
+        t.end_struct();
+        t.end_struct();
+    }
+
+    t.i64(2, size as i64);
+    t.i64(3, n_row as i64);
+    t.i64(5, offset as i64);
+    t.i64(6, size as i64);
+    t.i16(7, ordinal as i16);
+}
+
+fn chunk_offset(c: &ColumnChunk) -> usize {
+    c.dictionary_page_offset.unwrap_or(c.data_page_offset)
+}
+
+fn column_metadata(t: &mut Thrift, c: &ColumnChunk) {
+    let encodings: &[i32] = if c.dictionary {
+        &[PLAIN, RLE, RLE_DICTIONARY]
+    } else {
+        &[PLAIN, RLE]
+    };
+
+    t.i32(1, c.physical_type);
+
+    t.begin_list(2, I32, encodings.len());
+    for &e in encodings {
+        t.list_i32(e);
+    }
+
+    t.begin_list(3, BINARY, 1);
+    t.list_binary(c.name.as_bytes());
+
+    // uncompressed
+    t.i32(4, 0);
+    t.i64(5, c.num_values as i64);
+    t.i64(6, c.size as i64);
+    t.i64(7, c.size as i64);
+    t.i64(9, c.data_page_offset as i64);
+
+    if let Some(offset) = c.dictionary_page_offset {
+        t.i64(11, offset as i64);
+    }
+}
+
+// https://www.rfc-editor.org/rfc/rfc4648#section-4
+fn base64(x: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity((x.len() + 2) / 3 * 4);
+
+    for chunk in x.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+// THRIFT ----------------------------------------------------------------------
+// the thrift compact protocol, just what's needed to write the structs above,
+// see https://github.com/apache/thrift/blob/master/doc/specs/thrift-compact-protocol.md
+//
+// each field starts with the difference between its id and the last field's
+// in the same struct, so nested structs keep a stack of last ids.
+const I16: u8 = 4;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+struct Thrift {
+    buf: Vec<u8>,
+    last: Vec<i16>,
+}
+
+impl Default for Thrift {
+    fn default() -> Self {
+        Thrift {
+            buf: Vec::new(),
+            last: vec![0],
+        }
+    }
+}
+
+impl Thrift {
+    fn field(&mut self, id: i16, field_type: u8) {
+        let last = self.last.last_mut().unwrap();
+        let delta = id - *last;
+
+        if (1..=15).contains(&delta) {
+            self.buf.push((delta as u8) << 4 | field_type);
+        } else {
+            self.buf.push(field_type);
+            varint(&mut self.buf, zigzag(id as i64));
+        }
+
+        *last = id;
+    }
+
+    fn bool(&mut self, id: i16, x: bool) {
+        self.field(id, if x { 1 } else { 2 });
+    }
+
+    fn i16(&mut self, id: i16, x: i16) {
+        self.field(id, I16);
+        varint(&mut self.buf, zigzag(x as i64));
+    }
+
+    fn i32(&mut self, id: i16, x: i32) {
+        self.field(id, I32);
+        self.list_i32(x);
+    }
+
+    fn i64(&mut self, id: i16, x: i64) {
+        self.field(id, I64);
+        varint(&mut self.buf, zigzag(x));
+    }
+
+    fn binary(&mut self, id: i16, x: &[u8]) {
+        self.field(id, BINARY);
+        self.list_binary(x);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.last.push(0);
+    }
+
+    fn end_struct(&mut self) {
+        self.stop();
+        self.last.pop();
+    }
+
+    fn empty_struct(&mut self, id: i16) {
+        self.begin_struct(id);
+        self.end_struct();
+    }
+
+    fn stop(&mut self) {
+        self.buf.push(0);
+    }
+
+    fn begin_list(&mut self, id: i16, element_type: u8, size: usize) {
+        self.field(id, LIST);
+
+        if size < 15 {
+            self.buf.push((size as u8) << 4 | element_type);
+        } else {
+            self.buf.push(0xF0 | element_type);
+            varint(&mut self.buf, size as u64);
+        }
+    }
+
+    // a struct in a list, closed with `end_struct()`
+    fn begin_element(&mut self) {
+        self.last.push(0);
+    }
+
+    fn list_i32(&mut self, x: i32) {
+        varint(&mut self.buf, zigzag(x as i64));
+    }
+
+    fn list_binary(&mut self, x: &[u8]) {
+        varint(&mut self.buf, x.len() as u64);
+        self.buf.extend_from_slice(x);
+    }
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
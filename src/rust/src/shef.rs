@@ -0,0 +1,355 @@
+use extendr_api::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use crate::dates;
+use crate::units;
+use crate::{named_doubles, named_strings, StationDataSet, StationElement};
+
+// SHEF ------------------------------------------------------------------------
+// station data encoded as Standard Hydrometeorological Exchange Format text,
+// the way River Forecast Centers ingest it. hourly and daily series become .E
+// messages, one for each run of evenly spaced values, and coarser durations
+// become one .A message per value. values are identified by the station's
+// `shef_id` and a seven character parameter code, built from
+//
+// - PE, the physical element, from the physical elements reference list
+// - D, the duration, from the element's `duration_name`
+// - T, the type: "R" for observed and "P" for derived data
+// - S, the source: "Z", or the element's ordinal for its second through ninth
+//   sensors
+// - E, the extremum: "X" or "N" for daily maximums and minimums, else "Z"
+// - P, the probability, always "Z"
+//
+// hourly values are already in UTC (see `apply_time_zones()`), so they're sent
+// in zone "Z". everything coarser is dated in the station's standard time, so
+// is sent in its standard time zone, at the end (DH24) of the day it falls on.
+// monthly and longer values that only have a year and month are placed on the
+// last day of their period. values are written to the element's data
+// precision in its stored unit, behind a DUS keyword when that unit is metric,
+// since SHEF assumes english units otherwise.
+
+// no line may run past 80 characters
+const LINE_WIDTH: usize = 80;
+
+// values are kept in whole seconds
+const DAY: i64 = dates::SECONDS_PER_DAY as i64;
+
+// what's needed on top of the data to encode it, passed from R
+pub(crate) struct ShefCodes {
+    // station triplet to shef id
+    shef_ids: HashMap<String, String>,
+    // station triplet to hours from UTC
+    time_zones: HashMap<String, f64>,
+    // element code to SHEF physical element code
+    pe_codes: HashMap<String, String>,
+    // element code to the name of its function, e.g. "Maximum"
+    functions: HashMap<String, String>,
+}
+
+impl ShefCodes {
+    pub(crate) fn new(
+        shef_ids: &Robj,
+        time_zones: &Robj,
+        pe_codes: &Robj,
+        functions: &Robj,
+    ) -> Self {
+        let codes = |x: &Robj| -> HashMap<String, String> {
+            named_strings(x)
+                .into_iter()
+                .map(|(k, v)| (k, v.trim().to_uppercase()))
+                .filter(|(_, v)| !v.is_empty())
+                .collect()
+        };
+
+        ShefCodes {
+            shef_ids: codes(shef_ids),
+            time_zones: named_doubles(time_zones),
+            pe_codes: codes(pe_codes),
+            functions: named_strings(functions).into_iter().collect(),
+        }
+    }
+}
+
+// a station element that couldn't be encoded, and why
+struct Unmapped {
+    station_triplet: String,
+    element_code: String,
+    reason: &'static str,
+}
+
+fn duration_code(duration_name: &str) -> Option<char> {
+    match duration_name {
+        "HOURLY" => Some('H'),
+        "DAILY" => Some('D'),
+        "SEMIMONTHLY" => Some('N'),
+        "MONTHLY" => Some('M'),
+        "CALENDAR_YEAR" | "WATER_YEAR" => Some('Y'),
+        _ => None,
+    }
+}
+
+// AWDB's element functions are named like "Maximum" or "Minimum", and SHEF
+// only has extremum codes for those over a day
+fn extremum_code(function: Option<&String>, duration: char) -> char {
+    let function = function.map(|f| f.to_lowercase()).unwrap_or_default();
+
+    match duration {
+        'D' if function.starts_with("max") => 'X',
+        'D' if function.starts_with("min") => 'N',
+        _ => 'Z',
+    }
+}
+
+// standard time zone codes for the offsets AWDB stations use. anything else is
+// sent as UTC.
+fn zone_code(offset: Option<f64>) -> &'static str {
+    match offset.map(|x| x as i32) {
+        Some(-4) => "AS",
+        Some(-5) => "ES",
+        Some(-6) => "CS",
+        Some(-7) => "MS",
+        Some(-8) => "PS",
+        Some(-9) => "LS",
+        Some(-10) => "HS",
+        Some(-11) => "BS",
+        _ => "Z",
+    }
+}
+
+fn parameter_code(pe: &str, se: &StationElement, duration: char, extremum: char) -> String {
+    let type_code = if se.derived_data { 'P' } else { 'R' };
+    let source_code = match se.ordinal {
+        2..=9 => char::from(b'0' + se.ordinal as u8),
+        _ => 'Z',
+    };
+
+    format!("{pe}{duration}{type_code}{source_code}{extremum}Z")
+}
+
+// the civil date and time of day for seconds since the epoch
+fn date_time(seconds: i64) -> (String, i64, i64) {
+    let days = seconds.div_euclid(DAY);
+    let rest = seconds.rem_euclid(DAY);
+
+    (
+        dates::format_date(days).replace('-', ""),
+        rest / 3600,
+        rest % 3600 / 60,
+    )
+}
+
+fn format_value(x: Option<f64>, precision: i32) -> String {
+    match x {
+        Some(v) => format!("{:.*}", precision.max(0) as usize, v),
+        None => "M".to_string(),
+    }
+}
+
+impl StationDataSet {
+    // seconds since the epoch for value j of a station element. values
+    // without a date are placed on the last day of their period, from their
+    // year, month and month part.
+    fn shef_seconds(&self, se: &StationElement, j: usize) -> Option<i64> {
+        let v = &self.values;
+
+        if let Some(d) = v.date[j] {
+            return Some(d as i64);
+        }
+
+        let year = v.year[j]?;
+
+        let days = match (se.duration_name.as_str(), v.month[j]) {
+            ("WATER_YEAR", _) => dates::days_from_civil(year, 9, 30),
+            ("CALENDAR_YEAR", _) => dates::days_from_civil(year, 12, 31),
+            (_, Some(month)) if (1..=12).contains(&month) => {
+                let month = month as u32;
+                let first_half = v.month_part[j].as_deref().map_or(false, |p| {
+                    p.starts_with('1') || p.eq_ignore_ascii_case("first")
+                });
+                let day = if first_half {
+                    15
+                } else {
+                    dates::days_in_month(year, month)
+                };
+
+                dates::days_from_civil(year, month, day)
+            }
+            _ => return None,
+        };
+
+        Some(days * DAY)
+    }
+
+    // SHEF text for every station element that can be mapped, one line per
+    // element of the vector, and a table of those that couldn't be
+    pub(crate) fn encode_shef(&self, codes: &ShefCodes) -> Robj {
+        let mut lines: Vec<String> = Vec::new();
+        let mut unmapped: Vec<Unmapped> = Vec::new();
+        let mut seen: HashSet<(String, String)> = HashSet::new();
+
+        for (i, se) in self.station_element.iter().enumerate() {
+            let triplet = self.station_triplet[i].to_string();
+
+            let mut skip = |reason: &'static str| {
+                unmapped.push(Unmapped {
+                    station_triplet: triplet.clone(),
+                    element_code: se.element_code.clone(),
+                    reason,
+                })
+            };
+
+            let Some(shef_id) = codes.shef_ids.get(&triplet) else {
+                skip("station has no shef_id");
+                continue;
+            };
+
+            let Some(pe) = codes.pe_codes.get(&se.element_code) else {
+                skip("element has no SHEF physical element code");
+                continue;
+            };
+
+            let Some(duration) = duration_code(&se.duration_name) else {
+                skip("duration has no SHEF duration code");
+                continue;
+            };
+
+            let extremum = extremum_code(codes.functions.get(&se.element_code), duration);
+            let parameter = parameter_code(pe, se, duration, extremum);
+
+            // e.g. soil moisture at several depths, which SHEF can't tell apart
+            if !seen.insert((shef_id.clone(), parameter.clone())) {
+                skip("parameter code already used at this station");
+                continue;
+            }
+
+            let parameter = if units::is_metric(&se.stored_unit_code) {
+                format!("DUS/{parameter}")
+            } else {
+                parameter
+            };
+
+            let mut values: Vec<(i64, Option<f64>)> = (self.offsets[i]..self.offsets[i + 1])
+                .filter_map(|j| Some((self.shef_seconds(se, j)?, self.values.value[j])))
+                .collect();
+
+            values.sort_by_key(|(s, _)| *s);
+            values.dedup_by_key(|(s, _)| *s);
+
+            let zone = match duration {
+                'H' => "Z",
+                _ => zone_code(codes.time_zones.get(&triplet).copied()),
+            };
+
+            let message = Message {
+                shef_id,
+                zone,
+                parameter: &parameter,
+                precision: se.data_precision,
+            };
+
+            match duration {
+                'H' => message.encode_e(&values, 3600, "DIH01", &mut lines),
+                'D' => message.encode_e(&values, DAY, "DID01", &mut lines),
+                _ => message.encode_a(&values, &mut lines),
+            }
+        }
+
+        let mut out = lines.into_robj();
+
+        let mut table = data_frame!(
+            station_triplet = unmapped
+                .iter()
+                .map(|u| u.station_triplet.as_str())
+                .collect::<Vec<_>>(),
+            element_code = unmapped
+                .iter()
+                .map(|u| u.element_code.as_str())
+                .collect::<Vec<_>>(),
+            reason = unmapped.iter().map(|u| u.reason).collect::<Vec<_>>()
+        );
+
+        table.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+        out.set_attrib("unmapped", table).unwrap();
+
+        out
+    }
+}
+
+// the parts of a message shared by every value of a station element
+struct Message<'a> {
+    shef_id: &'a str,
+    zone: &'a str,
+    parameter: &'a str,
+    precision: i32,
+}
+
+impl Message<'_> {
+    // the positional fields, e.g. ".E ABCC1 20240115 PS DH24". hourly values
+    // are at their time of day, and anything coarser at the end of its day.
+    fn header(&self, format: &str, seconds: i64, hourly: bool) -> String {
+        let (date, hour, minute) = date_time(seconds);
+
+        let time = if hourly {
+            format!("DH{hour:02}{minute:02}")
+        } else {
+            "DH24".to_string()
+        };
+
+        format!("{format} {} {date} {} {time}", self.shef_id, self.zone)
+    }
+
+    // one .A message per value
+    fn encode_a(&self, values: &[(i64, Option<f64>)], lines: &mut Vec<String>) {
+        for (seconds, value) in values.iter() {
+            lines.push(format!(
+                "{}/{} {}",
+                self.header(".A", *seconds, false),
+                self.parameter,
+                format_value(*value, self.precision)
+            ));
+        }
+    }
+
+    // one .E message per run of values `step` seconds apart, wrapped onto
+    // numbered continuation lines
+    fn encode_e(
+        &self,
+        values: &[(i64, Option<f64>)],
+        step: i64,
+        interval: &str,
+        lines: &mut Vec<String>,
+    ) {
+        let mut start = 0;
+
+        while start < values.len() {
+            let mut end = start + 1;
+
+            while end < values.len() && values[end].0 - values[end - 1].0 == step {
+                end += 1;
+            }
+
+            let mut line = format!(
+                "{}/{}/{interval}",
+                self.header(".E", values[start].0, step < DAY),
+                self.parameter
+            );
+            let mut continuation = 0;
+
+            for (_, value) in values[start..end].iter() {
+                let value = format_value(*value, self.precision);
+
+                if line.len() + 1 + value.len() > LINE_WIDTH {
+                    lines.push(line);
+                    continuation = continuation % 99 + 1;
+                    line = format!(".E{continuation} {value}");
+                } else {
+                    line.push('/');
+                    line.push_str(&value);
+                }
+            }
+
+            lines.push(line);
+            start = end;
+        }
+    }
+}
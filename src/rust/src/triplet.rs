@@ -0,0 +1,146 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::TripletError;
+
+// STATION TRIPLETS ------------------------------------------------------------
+// stations are identified by `station_id:state_code:network_code`, e.g.
+// "1234:UT:SNTL". when querying, any part may be the `*` wildcard, e.g.
+// "*:*:SNTL" for every SNOTEL station.
+const WILDCARD: &str = "*";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct StationTriplet {
+    pub(crate) station_id: String,
+    pub(crate) state_code: String,
+    pub(crate) network_code: String,
+}
+
+impl StationTriplet {
+    // state and network codes are upper cased, since AWDB codes always are
+    pub(crate) fn new(
+        station_id: &str,
+        state_code: &str,
+        network_code: &str,
+    ) -> Result<Self, TripletError> {
+        let triplet = || format!("{station_id}:{state_code}:{network_code}");
+
+        let station_id = station_id.trim();
+        let state_code = state_code.trim().to_ascii_uppercase();
+        let network_code = network_code.trim().to_ascii_uppercase();
+
+        // station ids are mostly numbers, but snow courses and some other
+        // networks use letters too, e.g. "06G01S"
+        let valid_id = !station_id.is_empty()
+            && station_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '*'));
+
+        if !valid_id {
+            return Err(TripletError::new(
+                &triplet(),
+                "station id must be letters, numbers, or `*`",
+            ));
+        }
+
+        let valid_state = state_code == WILDCARD
+            || (state_code.len() == 2 && state_code.chars().all(|c| c.is_ascii_alphabetic()));
+
+        if !valid_state {
+            return Err(TripletError::new(
+                &triplet(),
+                "state code must be two letters or `*`",
+            ));
+        }
+
+        let valid_network = network_code == WILDCARD
+            || (!network_code.is_empty()
+                && network_code.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        if !valid_network {
+            return Err(TripletError::new(
+                &triplet(),
+                "network code must be letters, numbers, or `*`",
+            ));
+        }
+
+        Ok(StationTriplet {
+            station_id: station_id.to_string(),
+            state_code,
+            network_code,
+        })
+    }
+
+    // a triplet as AWDB returned it, taken as is so long as it has three
+    // non-empty parts. the rules in `new()` are for triplets from the user;
+    // AWDB's own ids needn't follow them, and one odd id shouldn't fail a
+    // whole response.
+    pub(crate) fn from_response(s: &str) -> Result<Self, TripletError> {
+        let parts: Vec<&str> = s.split(':').collect();
+
+        match parts[..] {
+            [station_id, state_code, network_code] if parts.iter().all(|part| !part.is_empty()) => {
+                Ok(StationTriplet {
+                    station_id: station_id.to_string(),
+                    state_code: state_code.to_string(),
+                    network_code: network_code.to_string(),
+                })
+            }
+            _ => Err(TripletError::new(
+                s,
+                "expected three non-empty parts of the form `station_id:state_code:network_code`",
+            )),
+        }
+    }
+}
+
+impl FromStr for StationTriplet {
+    type Err = TripletError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+
+        match parts[..] {
+            [station_id, state_code, network_code] => {
+                StationTriplet::new(station_id, state_code, network_code)
+            }
+            _ => Err(TripletError::new(
+                s,
+                "expected three parts of the form `station_id:state_code:network_code`",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StationTriplet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.station_id, self.state_code, self.network_code
+        )
+    }
+}
+
+// triplets in station data and forecasts come from AWDB, so only their shape
+// is checked
+impl<'de> Deserialize<'de> for StationTriplet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        StationTriplet::from_response(&s).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for StationTriplet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
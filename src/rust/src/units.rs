@@ -0,0 +1,243 @@
+use extendr_api::prelude::*;
+use std::collections::HashMap;
+
+use crate::errors::UnitError;
+
+// UNITS -----------------------------------------------------------------------
+// a registry of the unit codes AWDB uses (see the units reference list), each
+// with the factor and offset that take a value to the SI unit of its dimension:
+// si = value * factor + offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Length,
+    Temperature,
+    Volume,
+    Flow,
+    Speed,
+    Pressure,
+    RadiationFlux,
+    RadiationEnergy,
+    Conductivity,
+    Time,
+    // percents, angles, counts, volts and the like are never converted
+    Dimensionless,
+}
+
+impl Dimension {
+    fn si_code(&self) -> Option<&'static str> {
+        match self {
+            Dimension::Length => Some("m"),
+            Dimension::Temperature => Some("degC"),
+            Dimension::Volume => Some("m3"),
+            Dimension::Flow => Some("m3/s"),
+            Dimension::Speed => Some("m/s"),
+            Dimension::Pressure => Some("Pa"),
+            Dimension::RadiationFlux => Some("W/m2"),
+            Dimension::RadiationEnergy => Some("J/m2"),
+            Dimension::Conductivity => Some("S/m"),
+            Dimension::Time => Some("s"),
+            Dimension::Dimensionless => None,
+        }
+    }
+}
+
+struct Unit {
+    code: &'static str,
+    dimension: Dimension,
+    factor: f64,
+    offset: f64,
+}
+
+const fn unit(code: &'static str, dimension: Dimension, factor: f64) -> Unit {
+    Unit {
+        code,
+        dimension,
+        factor,
+        offset: 0.0,
+    }
+}
+
+const ACRE_FOOT: f64 = 1233.48183754752;
+const CUBIC_FOOT: f64 = 0.028316846592;
+
+const REGISTRY: &[Unit] = &[
+    // length
+    unit("m", Dimension::Length, 1.0),
+    unit("mm", Dimension::Length, 0.001),
+    unit("cm", Dimension::Length, 0.01),
+    unit("km", Dimension::Length, 1000.0),
+    unit("in", Dimension::Length, 0.0254),
+    unit("ft", Dimension::Length, 0.3048),
+    unit("mi", Dimension::Length, 1609.344),
+    // temperature
+    unit("degC", Dimension::Temperature, 1.0),
+    Unit {
+        code: "degF",
+        dimension: Dimension::Temperature,
+        factor: 5.0 / 9.0,
+        offset: -32.0 * 5.0 / 9.0,
+    },
+    Unit {
+        code: "K",
+        dimension: Dimension::Temperature,
+        factor: 1.0,
+        offset: -273.15,
+    },
+    // volume
+    unit("m3", Dimension::Volume, 1.0),
+    unit("hm3", Dimension::Volume, 1.0e6),
+    unit("mcm", Dimension::Volume, 1.0e6),
+    unit("ac-ft", Dimension::Volume, ACRE_FOOT),
+    unit("kaf", Dimension::Volume, ACRE_FOOT * 1000.0),
+    unit("ft3", Dimension::Volume, CUBIC_FOOT),
+    unit("gal", Dimension::Volume, 0.003785411784),
+    // flow
+    unit("m3/s", Dimension::Flow, 1.0),
+    unit("cms", Dimension::Flow, 1.0),
+    unit("cfs", Dimension::Flow, CUBIC_FOOT),
+    unit("kcfs", Dimension::Flow, CUBIC_FOOT * 1000.0),
+    unit("ac-ft/d", Dimension::Flow, ACRE_FOOT / 86400.0),
+    // speed
+    unit("m/s", Dimension::Speed, 1.0),
+    unit("km/h", Dimension::Speed, 1.0 / 3.6),
+    unit("mph", Dimension::Speed, 0.44704),
+    unit("kts", Dimension::Speed, 1852.0 / 3600.0),
+    // pressure
+    unit("Pa", Dimension::Pressure, 1.0),
+    unit("hPa", Dimension::Pressure, 100.0),
+    unit("mb", Dimension::Pressure, 100.0),
+    unit("kPa", Dimension::Pressure, 1000.0),
+    unit("inHg", Dimension::Pressure, 3386.389),
+    unit("psi", Dimension::Pressure, 6894.757293168),
+    // radiation
+    unit("W/m2", Dimension::RadiationFlux, 1.0),
+    unit("watt/m2", Dimension::RadiationFlux, 1.0),
+    unit("J/m2", Dimension::RadiationEnergy, 1.0),
+    unit("MJ/m2", Dimension::RadiationEnergy, 1.0e6),
+    unit("langley", Dimension::RadiationEnergy, 41840.0),
+    unit("ly", Dimension::RadiationEnergy, 41840.0),
+    // conductivity
+    unit("S/m", Dimension::Conductivity, 1.0),
+    unit("dS/m", Dimension::Conductivity, 0.1),
+    unit("mS/cm", Dimension::Conductivity, 0.1),
+    unit("uS/cm", Dimension::Conductivity, 1.0e-4),
+    unit("mmho/cm", Dimension::Conductivity, 0.1),
+    unit("umho/cm", Dimension::Conductivity, 1.0e-4),
+    // time
+    unit("s", Dimension::Time, 1.0),
+    unit("min", Dimension::Time, 60.0),
+    unit("hr", Dimension::Time, 3600.0),
+    unit("day", Dimension::Time, 86400.0),
+    // never converted
+    unit("pct", Dimension::Dimensionless, 1.0),
+    unit("%", Dimension::Dimensionless, 1.0),
+    unit("deg", Dimension::Dimensionless, 1.0),
+    unit("degree", Dimension::Dimensionless, 1.0),
+    unit("volt", Dimension::Dimensionless, 1.0),
+    unit("V", Dimension::Dimensionless, 1.0),
+    unit("ratio", Dimension::Dimensionless, 1.0),
+    unit("index", Dimension::Dimensionless, 1.0),
+    unit("count", Dimension::Dimensionless, 1.0),
+    unit("unitless", Dimension::Dimensionless, 1.0),
+];
+
+fn lookup(code: &str) -> Option<&'static Unit> {
+    REGISTRY.iter().find(|u| u.code == code)
+}
+
+// the units of the metric system, for formats like SHEF that need to be told
+// when values aren't in english units
+const METRIC: &[&str] = &[
+    "m", "mm", "cm", "km", "degC", "K", "m3", "hm3", "mcm", "m3/s", "cms", "m/s", "km/h", "Pa",
+    "hPa", "mb", "kPa", "W/m2", "watt/m2", "J/m2", "MJ/m2",
+];
+
+pub(crate) fn is_metric(code: &str) -> bool {
+    METRIC.contains(&code)
+}
+
+// which unit each element should end up in
+pub(crate) enum UnitTarget {
+    // the SI unit of whatever dimension the stored unit has
+    Si,
+    // a unit code per element code, e.g. `metric_unit_code` from the elements
+    // reference list. elements not in the map are left as stored.
+    ByElement(HashMap<String, String>),
+}
+
+impl UnitTarget {
+    // from R: NULL for no conversion, "si", or a character vector of unit codes
+    // named by element code
+    pub(crate) fn from_robj(x: &Robj) -> Option<Self> {
+        if x.is_null() {
+            return None;
+        }
+
+        match x.names() {
+            Some(names) => Some(UnitTarget::ByElement(
+                names
+                    .zip(x.as_str_iter()?)
+                    .filter(|(_, v)| !v.is_na())
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )),
+            None if x.as_str() == Some("si") => Some(UnitTarget::Si),
+            None => None,
+        }
+    }
+
+    pub(crate) fn target_code<'a>(
+        &'a self,
+        element_code: &str,
+        from: &'a str,
+    ) -> std::result::Result<&'a str, UnitError> {
+        match self {
+            UnitTarget::Si => {
+                let unit = lookup(from).ok_or_else(|| UnitError::unrecognized(from))?;
+                Ok(unit.dimension.si_code().unwrap_or(from))
+            }
+            UnitTarget::ByElement(codes) => {
+                Ok(codes.get(element_code).map(|x| x.as_str()).unwrap_or(from))
+            }
+        }
+    }
+}
+
+// a linear conversion between two unit codes of the same dimension
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Conversion {
+    factor: f64,
+    offset: f64,
+}
+
+impl Conversion {
+    pub(crate) fn new(from: &str, to: &str) -> std::result::Result<Self, UnitError> {
+        if from == to {
+            return Ok(Conversion {
+                factor: 1.0,
+                offset: 0.0,
+            });
+        }
+
+        let a = lookup(from).ok_or_else(|| UnitError::unrecognized(from))?;
+        let b = lookup(to).ok_or_else(|| UnitError::unrecognized(to))?;
+
+        if a.dimension != b.dimension || a.dimension == Dimension::Dimensionless {
+            return Err(UnitError::incompatible(from, to));
+        }
+
+        // from -> SI -> to
+        Ok(Conversion {
+            factor: a.factor / b.factor,
+            offset: (a.offset - b.offset) / b.factor,
+        })
+    }
+
+    pub(crate) fn is_identity(&self) -> bool {
+        self.factor == 1.0 && self.offset == 0.0
+    }
+
+    pub(crate) fn apply(&self, x: Option<f64>) -> Option<f64> {
+        x.map(|v| v * self.factor + self.offset)
+    }
+}
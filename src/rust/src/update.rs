@@ -0,0 +1,410 @@
+use extendr_api::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::dates::{self, SECONDS_PER_DAY};
+use crate::errors::UnitError;
+use crate::triplet::StationTriplet;
+use crate::{StationDataSet, StationElement, ValueColumns};
+
+// UPDATES ---------------------------------------------------------------------
+// a previous `get_elements()` result, wide or long, is read back into a
+// station data set so it can be brought up to date: find the last date each
+// station element was observed, then merge in values requested since. values
+// are matched on station triplet, element code, height depth, ordinal and
+// date, and a newly requested value replaces the old one, since AWDB revises
+// provisional data after the fact.
+//
+// tables hold dates as Date or POSIXct, which go back to seconds since the
+// epoch here, as they were before `ValueColumns::into_robj()`. hourly values
+// are already in UTC, so nothing is shifted again.
+
+// a station element, and the values that go with it
+type Series = (StationTriplet, StationElement, ValueColumns);
+
+// what identifies a station element across tables
+type SeriesKey = (String, String, i32, Option<i32>, String);
+
+fn series_key(triplet: &StationTriplet, se: &StationElement) -> SeriesKey {
+    (
+        triplet.to_string(),
+        se.element_code.clone(),
+        se.ordinal,
+        se.height_depth,
+        se.duration_name.clone(),
+    )
+}
+
+// columns of a data frame by name, reading anything missing (e.g. dropped for
+// being empty) as all NA
+struct Columns {
+    columns: HashMap<String, Robj>,
+    n: usize,
+}
+
+impl Columns {
+    fn new(df: &Robj) -> std::result::Result<Self, String> {
+        let lst = List::try_from(df).map_err(|_| "expected a data frame".to_string())?;
+
+        let columns: HashMap<String, Robj> =
+            lst.iter().map(|(name, x)| (name.to_string(), x)).collect();
+
+        let n = columns.values().next().map_or(0, |x| x.len());
+
+        Ok(Columns { columns, n })
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.columns.contains_key(name)
+    }
+
+    fn get(&self, name: &str) -> Option<&Robj> {
+        self.columns.get(name)
+    }
+
+    fn read<T: Clone>(&self, name: &str, f: fn(&Robj) -> Vec<Option<T>>) -> Vec<Option<T>> {
+        match self.get(name) {
+            Some(x) => f(x),
+            None => vec![None; self.n],
+        }
+    }
+
+    fn doubles(&self, name: &str) -> Vec<Option<f64>> {
+        self.read(name, crate::doubles)
+    }
+
+    fn integers(&self, name: &str) -> Vec<Option<i32>> {
+        self.read(name, crate::integers)
+    }
+
+    fn strings(&self, name: &str) -> Vec<Option<String>> {
+        self.read(name, crate::strings)
+    }
+
+    fn logicals(&self, name: &str) -> Vec<Option<bool>> {
+        match self.get(name).and_then(|x| x.as_logical_slice()) {
+            Some(x) => x
+                .iter()
+                .map(|v| if v.is_na() { None } else { Some(v.is_true()) })
+                .collect(),
+            None => vec![None; self.n],
+        }
+    }
+
+    // Date columns hold days and POSIXct columns hold seconds
+    fn seconds(&self, name: &str) -> Vec<Option<f64>> {
+        let x = self.doubles(name);
+
+        match self.get(name) {
+            Some(col) if col.inherits("Date") => x
+                .into_iter()
+                .map(|d| d.map(|d| d * SECONDS_PER_DAY))
+                .collect(),
+            _ => x,
+        }
+    }
+
+    // the "YYYY-MM-DD" strings AWDB sends for station element dates
+    fn date_strings(&self, name: &str) -> Vec<String> {
+        self.doubles(name)
+            .into_iter()
+            .map(|d| d.map_or(String::new(), |d| dates::format_date(d as i64)))
+            .collect()
+    }
+
+    fn values(&self) -> ValueColumns {
+        ValueColumns {
+            date: self.seconds("date"),
+            month: self.integers("month"),
+            month_part: self.strings("month_part"),
+            year: self.integers("year"),
+            collection_date: self.seconds("collection_date"),
+            value: self.doubles("value"),
+            qc_flag: self.strings("qc_flag"),
+            qa_flag: self.strings("qa_flag"),
+            orig_value: self.doubles("orig_value"),
+            orig_qc_flag: self.strings("orig_qc_flag"),
+            average: self.doubles("average"),
+            median: self.doubles("median"),
+        }
+    }
+}
+
+impl StationDataSet {
+    // read back a table built by `station_dataset_robj()`, in either format
+    pub(crate) fn from_robj(df: &Robj) -> std::result::Result<Self, String> {
+        let columns = Columns::new(df)?;
+
+        let triplets = columns
+            .strings("station_triplet")
+            .into_iter()
+            .map(|t| {
+                let t = t.unwrap_or_default();
+                StationTriplet::from_response(&t)
+                    .map_err(|_| format!("invalid station triplet `{t}`"))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let element_code = columns.strings("element_code");
+        let ordinal = columns.integers("ordinal");
+        let height_depth = columns.integers("height_depth");
+        let duration_name = columns.strings("duration_name");
+        let data_precision = columns.integers("data_precision");
+        let stored_unit_code = columns.strings("stored_unit_code");
+        let original_unit_code = columns.strings("original_unit_code");
+        let begin_date = columns.date_strings("begin_date");
+        let end_date = columns.date_strings("end_date");
+        let derived_data = columns.logicals("derived_data");
+
+        let element = |i: usize| StationElement {
+            element_code: element_code[i].clone().unwrap_or_default(),
+            ordinal: ordinal[i].unwrap_or(1),
+            height_depth: height_depth[i],
+            duration_name: duration_name[i].clone().unwrap_or_default(),
+            data_precision: data_precision[i].unwrap_or(0),
+            stored_unit_code: stored_unit_code[i].clone().unwrap_or_default(),
+            original_unit_code: original_unit_code[i].clone().unwrap_or_default(),
+            begin_date: begin_date[i].clone(),
+            end_date: end_date[i].clone(),
+            derived_data: derived_data[i].unwrap_or(false),
+        };
+
+        let mut sd = StationDataSet::default();
+
+        if let Some(nested) = columns.get("element_values") {
+            // wide: a nested table of values in each row
+            let nested = List::try_from(nested)
+                .map_err(|_| "`element_values` must be a list column".to_string())?;
+
+            for (i, (_, values)) in nested.iter().enumerate() {
+                let mut values = if values.is_null() {
+                    ValueColumns::default()
+                } else {
+                    Columns::new(&values)?.values()
+                };
+
+                sd.station_triplet.push(triplets[i].clone());
+                sd.station_element.push(element(i));
+                sd.values.append(&mut values);
+                sd.offsets.push(sd.values.len());
+            }
+        } else if columns.has("date") || columns.has("year") {
+            // long: one row per value, in runs of the same station element
+            sd.values = columns.values();
+
+            for (i, triplet) in triplets.iter().enumerate() {
+                let se = element(i);
+                let same = match (sd.station_triplet.last(), sd.station_element.last()) {
+                    (Some(t), Some(last)) => series_key(t, last) == series_key(triplet, &se),
+                    _ => false,
+                };
+
+                if same {
+                    *sd.offsets.last_mut().unwrap() = i + 1;
+                } else {
+                    sd.station_triplet.push(triplet.clone());
+                    sd.station_element.push(se);
+                    sd.offsets.push(i + 1);
+                }
+            }
+        } else {
+            return Err("expected an `element_values` or `date` column".to_string());
+        }
+
+        // a table that was filtered or reordered may have split a station
+        // element into more than one run
+        Ok(sd.merge_windows())
+    }
+
+    fn into_series(mut self) -> Vec<Series> {
+        let n = self.station_element.len();
+        let mut series = Vec::with_capacity(n);
+
+        for i in (0..n).rev() {
+            let values = self.values.split_off(self.offsets[i]);
+            let se = self.station_element.pop().unwrap();
+            let triplet = self.station_triplet.pop().unwrap();
+
+            series.push((triplet, se, values));
+        }
+
+        series.reverse();
+        series
+    }
+
+    fn from_series(series: Vec<Series>) -> Self {
+        let mut sd = StationDataSet::default();
+
+        for (triplet, se, mut values) in series.into_iter() {
+            sd.station_triplet.push(triplet);
+            sd.station_element.push(se);
+            sd.values.append(&mut values);
+            sd.offsets.push(sd.values.len());
+        }
+
+        sd
+    }
+
+    // the latest date with a value for each station element, in days since
+    // the epoch, or None if it has no values. durations without a date (e.g.
+    // SEMIMONTHLY, MONTHLY or WATER_YEAR) give the first day of the last
+    // period with a value, so requesting from it gets that whole period again.
+    pub(crate) fn last_observed(&self) -> std::result::Result<Vec<Option<f64>>, String> {
+        (0..self.station_element.len())
+            .map(|i| {
+                let se = &self.station_element[i];
+
+                (self.offsets[i]..self.offsets[i + 1])
+                    .filter(|&j| self.values.value[j].is_some())
+                    .map(|j| match self.values.date[j] {
+                        Some(d) => Ok((d / SECONDS_PER_DAY).floor()),
+                        None => period_begin(&se.duration_name, &self.values, j)
+                            .map(|d| d as f64)
+                            .ok_or_else(|| {
+                                format!(
+                                    "can't tell when {} {} values at `{}` were observed",
+                                    se.duration_name, se.element_code, self.station_triplet[i]
+                                )
+                            }),
+                    })
+                    .try_fold(None, |last: Option<f64>, d| {
+                        d.map(|d| Some(last.map_or(d, |l| l.max(d))))
+                    })
+            })
+            .collect()
+    }
+
+    // merge `newer` into this data set. station elements keep their order,
+    // with any new ones added at the end, and take their metadata from the
+    // newer table, with the period of record widened to cover both. values
+    // are sorted by date, and where both tables have a value for the same
+    // date the newer one wins.
+    pub(crate) fn update(self, newer: StationDataSet) -> std::result::Result<Self, UnitError> {
+        let mut series = self.into_series();
+        let mut index: HashMap<SeriesKey, usize> = series
+            .iter()
+            .enumerate()
+            .map(|(i, (t, se, _))| (series_key(t, se), i))
+            .collect();
+
+        for (triplet, se, values) in newer.into_series() {
+            let key = series_key(&triplet, &se);
+
+            let Some(&i) = index.get(&key) else {
+                index.insert(key, series.len());
+                series.push((triplet, se, values));
+                continue;
+            };
+
+            let (_, old_se, old_values) = &mut series[i];
+
+            if old_se.stored_unit_code != se.stored_unit_code {
+                return Err(
+                    UnitError::mismatch(&old_se.stored_unit_code, &se.stored_unit_code)
+                        .at(&se.element_code, &triplet.to_string()),
+                );
+            }
+
+            let mut se = se;
+
+            if !old_se.begin_date.is_empty()
+                && (se.begin_date.is_empty() || old_se.begin_date < se.begin_date)
+            {
+                se.begin_date = old_se.begin_date.clone();
+            }
+
+            if old_se.end_date > se.end_date {
+                se.end_date = old_se.end_date.clone();
+            }
+
+            *old_se = se;
+            *old_values = merge_values(old_values, values);
+        }
+
+        Ok(StationDataSet::from_series(series))
+    }
+}
+
+// the old values that weren't requested again, then the new ones, sorted by
+// date (or year, month and month part, for durations without a date)
+fn merge_values(old: &ValueColumns, mut new: ValueColumns) -> ValueColumns {
+    let replaced: HashSet<_> = (0..new.len()).map(|j| new.key(j)).collect();
+    let kept: Vec<usize> = (0..old.len())
+        .filter(|&j| !replaced.contains(&old.key(j)))
+        .collect();
+
+    let mut merged = old.select(&kept);
+    merged.append(&mut new);
+
+    let mut order: Vec<usize> = (0..merged.len()).collect();
+
+    order.sort_by(|&a, &b| {
+        let date = |i: usize| merged.date[i];
+
+        date(a)
+            .partial_cmp(&date(b))
+            .unwrap_or(Ordering::Equal)
+            .then(merged.year[a].cmp(&merged.year[b]))
+            .then(merged.month[a].cmp(&merged.month[b]))
+            .then(merged.month_part[a].cmp(&merged.month_part[b]))
+    });
+
+    merged.select(&order)
+}
+
+// the first day of the period value j covers, in days since the epoch, from
+// its year, month and month part, for durations without a date. water years
+// start on Oct 1 of the year before, and the second half of a month on the
+// 16th.
+fn period_begin(duration: &str, values: &ValueColumns, j: usize) -> Option<i64> {
+    let year = values.year[j]?;
+
+    match (duration, values.month[j]) {
+        ("WATER_YEAR", _) => Some(dates::days_from_civil(year - 1, 10, 1)),
+        ("CALENDAR_YEAR", _) => Some(dates::days_from_civil(year, 1, 1)),
+        ("MONTHLY", Some(month)) if (1..=12).contains(&month) => {
+            Some(dates::days_from_civil(year, month as u32, 1))
+        }
+        ("SEMIMONTHLY", Some(month)) if (1..=12).contains(&month) => {
+            let first_half = values.month_part[j]
+                .as_deref()
+                .map(|p| p.starts_with('1') || p.eq_ignore_ascii_case("first"))?;
+
+            Some(dates::days_from_civil(
+                year,
+                month as u32,
+                if first_half { 1 } else { 16 },
+            ))
+        }
+        _ => None,
+    }
+}
+
+// one row per station element with the last date it was observed
+pub(crate) fn last_observed_robj(sd: &StationDataSet) -> std::result::Result<Robj, String> {
+    let last = sd.last_observed()?;
+    let se = &sd.station_element;
+
+    let mut df = data_frame!(
+        station_triplet = sd
+            .station_triplet
+            .iter()
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>(),
+        element_code = se
+            .iter()
+            .map(|e| e.element_code.as_str())
+            .collect::<Vec<_>>(),
+        ordinal = se.iter().map(|e| e.ordinal).collect::<Vec<_>>(),
+        height_depth = se.iter().map(|e| e.height_depth).collect::<Vec<_>>(),
+        duration_name = se
+            .iter()
+            .map(|e| e.duration_name.as_str())
+            .collect::<Vec<_>>(),
+        last_date = dates::date_robj(last)
+    );
+
+    df.set_class(&["tbl_df", "tbl", "data.frame"]).unwrap();
+
+    Ok(df)
+}